@@ -0,0 +1,27 @@
+//! The `#[repr(C)]` vtable of rust capabilities, shared by the daemon which loads them
+//! and the code generated by `vdm-capability-macros`, so that both sides agree on one layout.
+use std::os::raw::{c_char, c_int};
+
+/// The version of `VdmCapability`, checked by the daemon on load.
+pub const ABI_VERSION:u32 = 2;
+
+/// `(ptr,len)` pair of raw bytes, for `bytes` arguments and results.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct VdmBytes {
+    pub ptr: *const u8,
+    pub len: usize
+}
+
+/// The vtable returned by `vdm_capability_entry`: `call` the function by name,
+/// with the arguments as bytes, and return zero with the result or non-zero with the error message,
+/// either released by `free_bytes` with the allocator of the capability;
+/// `has` tells whether the function by name is exported, checked by the daemon on load.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct VdmCapability {
+    pub abi_version: u32,
+    pub call: extern "C" fn(*const c_char, *const VdmBytes, usize, *mut VdmBytes) -> c_int,
+    pub free_bytes: extern "C" fn(VdmBytes),
+    pub has: extern "C" fn(*const c_char) -> bool
+}
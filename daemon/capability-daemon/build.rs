@@ -0,0 +1,28 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+//pyo3 leaves libpython unlinked under "extension-module"; the unit test
+//harness is a standalone executable, so link it there (see `lib.rs`).
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=PYO3_PYTHON");
+
+    let python = env::var("PYO3_PYTHON").unwrap_or( "python3".into() );
+    let script = "import sysconfig; print(sysconfig.get_config_var('LIBDIR')); print(sysconfig.get_config_var('LDVERSION'))";
+    let output = Command::new(python).args( ["-c", script] ).output();
+
+    let mut link = String::new();
+    if let Ok(output) = output {
+        let stdout = String::from_utf8_lossy( &output.stdout ).into_owned();
+        let mut lines = stdout.lines();
+        if let (true, Some(libdir), Some(version)) = (output.status.success(), lines.next(), lines.next()) {
+            println!("cargo:rustc-link-search=native={}", libdir);
+            link = format!("#[link(name = \"python{}\")]\nextern \"C\" {{}}\n", version);
+        }
+    }
+
+    let out_dir = env::var("OUT_DIR").unwrap();
+    fs::write( Path::new(&out_dir).join("libpython.rs"), link ).unwrap();
+}
@@ -0,0 +1,933 @@
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+use std::convert::TryFrom;
+use std::collections::{BTreeSet, HashMap};
+//
+use num_enum::TryFromPrimitive;
+use serde_json::{self, json, Value};
+//
+use serde_ipc::{FFIDescriptor, ArcFFIManager, MetaFunc, Arg};
+use serde_ipc::{ErrorCode, IPCError, IPCResult, CallToken};
+use serde_ipc::{IPCConfig, EventBus, Codec, Compression, FEATURE_PUSH_EVENTS, FEATURE_BINARY};
+
+/// (seq, header flags, data)
+pub type Message = (u32, u16, Vec<u8>);
+type SendResult = Result<(), mpsc::SendError<Message>>;
+
+/// header flag: more chunks of the same `seq` follow this one
+pub const FLAG_MORE:u16 = 0x0001;
+/// header flag: unsolicited event of subscription, with `seq` of 0
+pub const FLAG_EVENT:u16 = 0x0002;
+/// header flag: body is `[json_len:4B | json | blobs]`, the bytes referred as `{"$blob": [offset, len]}` in json
+pub const FLAG_BINARY:u16 = 0x0004;
+/// header flag: body is compressed with the negotiated compression, before chunked
+pub const FLAG_COMPRESSED:u16 = 0x0008;
+
+#[derive(Debug, Eq, PartialEq, TryFromPrimitive)]
+#[repr(u16)]
+enum Command {
+    ALIVE       = 0x00,
+    REGISTER    = 0x01,
+    UNREGISTER  = 0x02,
+    CALL        = 0x03,
+    ONE_WAY     = 0x04,
+    CHAIN_CALL  = 0x05,
+    SUBSCRIBE   = 0x06,
+    UNSUBSCRIBE = 0x07,
+    CANCEL      = 0x08,
+    BATCH       = 0x09
+}
+
+#[repr(C,packed)]
+pub struct ReqHeader {
+    pub seq: u32,
+    pub command: u16,
+    pub flags: u16,
+    pub size: u32
+}
+
+#[repr(C,packed)]
+#[derive(Clone, Copy)]
+pub struct ResHeader {
+    pub seq: u32,
+    pub flags: u16,
+    pub size: u32
+}
+
+pub const REQ_HEADER_LEN:usize = std::mem::size_of::<ReqHeader>();
+pub const RES_HEADER_LEN:usize = std::mem::size_of::<ResHeader>();
+
+impl ReqHeader {
+    pub fn from_bytes(buf:&[u8]) -> Self {
+        assert!( buf.len() >= REQ_HEADER_LEN );
+        unsafe{ std::ptr::read_unaligned( buf.as_ptr() as *const ReqHeader ) }
+    }
+}
+
+impl ResHeader {
+    pub fn to_bytes(self) -> [u8; RES_HEADER_LEN] {
+        unsafe{ std::mem::transmute(self) }
+    }
+}
+
+/// Wrap the result into response envelope:
+/// - `{"ok": <result>}` on success;
+/// - `{"err": {"code": <ErrorCode>, "message": <string>}}` on failure.
+fn _response<T: Into<Value>>(codec:Codec, result:Result<T, IPCError>) -> Vec<u8> {
+    let v = match result {
+        Ok(data) => json!({ "ok": data.into() }),
+        Err(e) => json!({ "err": {"code": e.code as u16, "message": e.message} })
+    };
+    codec.encode(&v).unwrap_or_default() //always representable
+}
+
+/// Wrap one call result into response envelope; the bytes result is appended to `blobs` if `binary`,
+/// or else encoded as `{"$b64": <string>}`.
+fn _envelope(result:IPCResult, blobs:&mut Vec<u8>, binary:bool) -> Value {
+    match result {
+        Ok(Arg::Bytes(data)) if binary => {
+            let v = json!({ "ok": {"$blob": [blobs.len(), data.len()]} });
+            blobs.extend(data);
+            v
+        },
+        Ok(Arg::Bytes(data)) => json!({ "ok": {"$b64": base64::encode(data)} }),
+        Ok(Arg::Str(data)) => json!({ "ok": data }),
+        Ok(Arg::Int(data)) => json!({ "ok": data }),
+        Ok(Arg::Float(data)) => json!({ "ok": data }),
+        Ok(Arg::Bool(data)) => json!({ "ok": data }),
+        Err(e) => json!({ "err": {"code": e.code as u16, "message": e.message} })
+    }
+}
+
+/// Encode the response body with its header flags, in binary layout if any blobs.
+fn _body(codec:Codec, v:&Value, blobs:Vec<u8>) -> (u16, Vec<u8>) {
+    let body = codec.encode(v).unwrap_or_default(); //always representable
+    if blobs.is_empty() {
+        return (0, body);
+    }
+    let mut buf = Vec::with_capacity(4 + body.len() + blobs.len());
+    buf.extend_from_slice( &(body.len() as u32).to_le_bytes() );
+    buf.extend(body);
+    buf.extend(blobs);
+    (FLAG_BINARY, buf)
+}
+
+fn _call_response(codec:Codec, result:IPCResult, binary:bool) -> (u16, Vec<u8>) {
+    let mut blobs = Vec::new();
+    let v = _envelope(result, &mut blobs, binary);
+    _body(codec, &v, blobs)
+}
+
+/// Wrap the batch (or chain) results as `{"ok": [<envelope>, ...]}` in order, or the error failing all of them.
+fn _batch_response(codec:Codec, results:Result<Vec<IPCResult>, IPCError>, binary:bool) -> (u16, Vec<u8>) {
+    match results {
+        Ok(results) => {
+            let mut blobs = Vec::new();
+            let items:Vec<Value> = results.into_iter().map(|res| _envelope(res, &mut blobs, binary)).collect();
+            _body(codec, &json!({ "ok": items }), blobs)
+        },
+        Err(e) => (0, _error(codec, e))
+    }
+}
+
+/// Build `{"sig": <srv_use_sig>, "spec": {<func>: {"restype", "args": [{<name>: <type>}, ...]}}}`.
+fn _register_reply(sig:String, spec:HashMap<String, MetaFunc>) -> Value {
+    let spec:serde_json::Map<String, Value> = spec.into_iter().map(|(name, func)| {
+        let args:Vec<Value> = func.args.into_iter().map(|(arg_name, arg_type)| {
+            json!({ arg_name: arg_type })
+        }).collect();
+        ( name, json!({ "restype": func.restype, "args": args }) )
+    }).collect();
+    json!({ "sig": sig, "spec": spec })
+}
+
+pub fn _error(codec:Codec, e:IPCError) -> Vec<u8> {
+    _response::<Value>( codec, Err(e) )
+}
+
+/// Compress the message body larger than `threshold`, unless it grows.
+pub fn _compress(compression:Compression, threshold:usize, message:Message) -> Message {
+    let (seq, flags, data) = message;
+    if compression==Compression::None || data.len() <= threshold {
+        return (seq, flags, data);
+    }
+    match compression.compress(&data) {
+        Ok(compressed) if compressed.len() < data.len() => (seq, flags | FLAG_COMPRESSED, compressed),
+        _ => (seq, flags, data)
+    }
+}
+
+fn _malformed(reason:&str) -> IPCError {
+    IPCError::new(ErrorCode::MalformedRequest, reason)
+}
+
+/// Split the request body into value and blobs, as indicated by `FLAG_BINARY`.
+fn _parse_body(codec:Codec, data:&[u8], flags:u16) -> Result<(Value, &[u8]), IPCError> {
+    let (data, blobs) = if flags & FLAG_BINARY == 0 { (data, &data[..0]) } else {
+        if data.len() < 4 {
+            return Err( _malformed("binary body truncated.") );
+        }
+        let json_len = u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize;
+        if data.len() - 4 < json_len {
+            return Err( _malformed("binary body truncated.") );
+        }
+        data[4..].split_at(json_len)
+    };
+    let v = codec.decode(data).map_err(|e| _malformed( &format!("request body is not valid {}: {}", codec.name(), e) ))?;
+    Ok( (v, blobs) )
+}
+
+/// Strings are passed as is, `{"$blob": [offset, len]}` and `{"$b64": <string>}` as bytes,
+/// and other values as their json text.
+fn _parse_arg(arg:&Value, blobs:&[u8]) -> Option<Arg> {
+    if let Some(blob) = arg.get("$blob") {
+        let (offset, len) = match blob.as_array()?.as_slice() {
+            [offset, len] => ( offset.as_u64()? as usize, len.as_u64()? as usize ),
+            _ => return None
+        };
+        let blob = blobs.get( offset..offset.checked_add(len)? )?;
+        return Some( Arg::Bytes(blob.to_vec()) );
+    }
+    if let Some(b64) = arg.get("$b64") {
+        return base64::decode( b64.as_str()? ).ok().map( Arg::Bytes );
+    }
+    match arg {
+        Value::String(s) => Some( Arg::Str(s.clone()) ),
+        _ => serde_json::to_string(arg).ok().map( Arg::Str )
+    }
+}
+
+fn _parse_args(v:&Value, blobs:&[u8]) -> Option<Vec<Arg>> {
+    v.as_array()?.iter().map(|arg| _parse_arg(arg, blobs)).collect()
+}
+
+/// Parse `{"name"}` request body.
+fn _parse_name(v:&Value) -> Result<String, IPCError> {
+    v.get("name").and_then(|x| x.as_str()).map(String::from)
+        .ok_or( _malformed("'name' field missing.") )
+}
+
+/// Parse `{"name", "sig"}` request body.
+fn _parse_usage(v:&Value) -> Result<(String, String), IPCError> {
+    let name = _parse_name(v)?;
+    let sig = v.get("sig").and_then(|x| x.as_str()).map(String::from)
+                .ok_or( _malformed("'sig' field missing.") )?;
+    Ok( (name, sig) )
+}
+
+/// Parse `{"name", "event"}` request body.
+fn _parse_subscription(v:&Value) -> Result<(String, String), IPCError> {
+    let name = v.get("name").and_then(|x| x.as_str());
+    let event = v.get("event").and_then(|x| x.as_str());
+    match (name, event) {
+        (Some(name), Some(event)) => Ok( (name.into(), event.into()) ),
+        _ => Err( _malformed("'name' or 'event' field missing.") )
+    }
+}
+
+/// Optional `"deadline"` of CALL/CHAIN_CALL, in milliseconds since received.
+fn _parse_deadline(v:&Value) -> Result<Option<Duration>, IPCError> {
+    match v.get("deadline") {
+        None | Some(Value::Null) => Ok(None),
+        Some(x) => x.as_u64().map( |ms| Some(Duration::from_millis(ms)) )
+                    .ok_or( _malformed("'deadline' field malformed.") )
+    }
+}
+
+fn _parse_seq(v:&Value) -> Result<u32, IPCError> {
+    v.get("seq").and_then(|x| x.as_u64()).map(|x| x as u32)
+        .ok_or( _malformed("'seq' field missing.") )
+}
+
+fn _parse_descriptor(v:&Value, blobs:&[u8]) -> Result<FFIDescriptor, IPCError> {
+    let descriptor = || -> Option<FFIDescriptor> {
+        let sig  = v.get("sig")?.as_str()?.to_string();
+        let func = v.get("func")?.as_str()?.to_string();
+        let args = _parse_args( v.get("args")?, blobs )?;
+        Some( (sig, func, args) )
+    };
+    descriptor().ok_or( _malformed("'sig', 'func' or 'args' field missing.") )
+}
+
+/// Parse `{<field>: [[sig, func, args], ...]}` request body.
+fn _parse_descriptors(v:&Value, field:&str, blobs:&[u8]) -> Result<Vec<FFIDescriptor>, IPCError> {
+    let descriptors = || -> Option<Vec<FFIDescriptor>> {
+        v.get(field)?.as_array()?.iter().map(|item| {
+            match item.as_array()?.as_slice() {
+                [sig, func, args] => Some((
+                    sig.as_str()?.to_string(), func.as_str()?.to_string(), _parse_args(args, blobs)?
+                )),
+                _ => None
+            }
+        }).collect()
+    };
+    descriptors().ok_or( _malformed(&format!("'{}' field malformed.", field)) )
+}
+
+/// Reassemble request frames and dispatch the commands of one connection.
+pub struct Dispatcher {
+    ffi: ArcFFIManager,
+    tx: mpsc::Sender<Message>,
+    config: IPCConfig,
+    codec: Codec,
+    compression: Compression,
+    // (capability name, srv_use_sig) pairs registered by this connection
+    capability_set: BTreeSet<(String, String)>,
+    // partially received (chunked) requests, at most `max_message_size` bytes in total
+    pending: HashMap<u32, Vec<u8>>,
+    pending_size: usize,
+    // rejected requests whose rest chunks are dropped
+    oversized: BTreeSet<u32>,
+    // attached on the first subscription
+    events: EventBus,
+    sink_id: Option<u64>,
+    // calls not answered yet, by seq
+    inflight: Arc<Mutex<HashMap<u32, CallToken>>>
+}
+
+impl Dispatcher {
+    pub fn new(ffi:ArcFFIManager, tx:mpsc::Sender<Message>, config:IPCConfig, codec:Codec) -> Self {
+        let events = ffi.lock().map(|ffi_obj| ffi_obj.events()).unwrap_or_default();
+        let compression = Compression::from_features(config.features);
+        Dispatcher{
+            ffi, tx, config, codec, compression,
+            capability_set: BTreeSet::new(),
+            pending: HashMap::new(),
+            pending_size: 0,
+            oversized: BTreeSet::new(),
+            events, sink_id: None,
+            inflight: Arc::new(Mutex::new( HashMap::new() ))
+        }
+    }
+
+    /// Create the token of call `seq`, tracked until answered.
+    fn track(&self, seq:u32, timeout:Option<Duration>) -> CallToken {
+        let token = CallToken::new(timeout);
+        if let Ok(mut inflight) = self.inflight.lock() {
+            inflight.insert(seq, token.clone());
+        }
+        token
+    }
+
+    /// Return the callback sending the response of call `seq`, wrapped by `respond`.
+    fn answer_to<R: 'static>(&self, seq:u32, respond:fn(Codec, R, bool) -> (u16, Vec<u8>)) -> impl FnOnce(R) + Send + 'static {
+        let (tx, inflight) = (self.tx.clone(), self.inflight.clone());
+        let (codec, binary) = (self.codec, self.config.features & FEATURE_BINARY != 0);
+        move |res| {
+            if let Ok(mut inflight) = inflight.lock() {
+                inflight.remove(&seq);
+            }
+            let (flags, data) = respond(codec, res, binary);
+            tx.send( (seq, flags, data) ).unwrap_or(());
+        }
+    }
+
+    /// Attach this connection to the event bus, forwarding events as EVENT frames.
+    fn attach_sink(&mut self) -> u64 {
+        if let Some(id) = self.sink_id {
+            return id;
+        }
+        let (tx, codec) = (self.tx.clone(), self.codec);
+        let id = self.events.attach(Box::new(move |name, event, data| {
+            let body = codec.encode( &json!({ "name":name, "event":event, "data":data }) ).unwrap_or_default();
+            tx.send( (0, FLAG_EVENT, body) ).is_ok()
+        }));
+        self.sink_id = Some(id);
+        id
+    }
+
+    pub fn too_large(&self) -> IPCError {
+        IPCError::new(ErrorCode::PayloadTooLarge,
+            format!("request exceeds {} bytes.", self.config.max_message_size))
+    }
+
+    /// Reply an error for the request without dispatching it.
+    pub fn reject(&self, seq:u32, err:IPCError) -> SendResult {
+        self.tx.send( (seq, 0, _error(self.codec, err)) )
+    }
+
+    /// Feed one request frame, and dispatch the command once all of its chunks arrived.
+    pub fn feed(&mut self, req_header:&ReqHeader, chunk:Vec<u8>) -> SendResult {
+        // reassemble the chunked request
+        let seq = req_header.seq;
+        let is_last = req_header.flags & FLAG_MORE == 0;
+        if self.oversized.contains(&seq) {
+            if is_last {
+                self.oversized.remove(&seq);
+            }
+            return Ok(())
+        }
+        let mut req_data = self.pending.remove(&seq).unwrap_or_default();
+        self.pending_size -= req_data.len();
+        req_data.extend(chunk);
+        if req_data.len() > self.config.max_message_size {
+            if !is_last {
+                self.oversized.insert(seq); //drop the rest chunks
+            }
+            return self.reject(seq, self.too_large())
+        }
+        if !is_last {
+            if self.pending_size + req_data.len() > self.config.max_message_size {
+                self.oversized.insert(seq); //drop the rest chunks
+                let err = IPCError::new(ErrorCode::PayloadTooLarge,
+                    format!("pending requests exceed {} bytes.", self.config.max_message_size));
+                return self.reject(seq, err)
+            }
+            self.pending_size += req_data.len();
+            self.pending.insert(seq, req_data);
+            return Ok(())
+        }
+        let mut flags = req_header.flags;
+        if flags & FLAG_COMPRESSED != 0 {
+            if self.compression==Compression::None {
+                return self.reject(seq, _malformed("compression not negotiated."));
+            }
+            req_data = match self.compression.decompress(&req_data, self.config.max_message_size) {
+                Ok(data) => data,
+                Err(e) => return self.reject(seq, _malformed( &format!("decompress failed: {}", e) ))
+            };
+            flags &= !FLAG_COMPRESSED;
+        }
+        self.dispatch(seq, req_header.command, flags, req_data)
+    }
+
+    fn dispatch(&mut self, seq:u32, command:u16, flags:u16, req_data:Vec<u8>) -> SendResult {
+        let (ffi, tx, codec) = (&self.ffi, &self.tx, self.codec);
+        // match command with its response
+        let command = match Command::try_from(command) {
+            Ok(command) => command,
+            Err(_) => {
+                let err = IPCError::new(ErrorCode::UnknownCommand, format!("unknown command 0x{:02x}.", command));
+                return tx.send( (seq, 0, _error(codec, err)) )
+            }
+        };
+        if (command==Command::SUBSCRIBE || command==Command::UNSUBSCRIBE) && self.config.features & FEATURE_PUSH_EVENTS == 0 {
+            let err = IPCError::new(ErrorCode::UnknownCommand, "push events not negotiated.".to_string());
+            return tx.send( (seq, 0, _error(codec, err)) )
+        }
+        if flags & FLAG_BINARY != 0 && self.config.features & FEATURE_BINARY == 0 {
+            return tx.send( (seq, 0, _error(codec, _malformed("binary body not negotiated."))) )
+        }
+        match command {
+            Command::ALIVE => {
+                //synchronized call
+                tx.send( (seq, 0, _response(codec, Ok(String::new()))) )?;
+            },
+            Command::REGISTER => {
+                //synchronized call
+                match _parse_body(codec, &req_data, flags).and_then(|(v,_)| _parse_name(&v)) {
+                    Ok(name) => {
+                        if let Ok(mut ffi_obj) = ffi.lock() {
+                            let capability_set = &mut self.capability_set;
+                            let result = ffi_obj.register_with_spec(&name).map(|(cid, spec)| {
+                                capability_set.insert( (name.clone(), cid.clone()) );
+                                _register_reply(cid, spec)
+                            });
+                            tx.send( (seq, 0, _response(codec, result)) )?;
+                        }
+                    },
+                    Err(e) => tx.send( (seq, 0, _error(codec, e)) )?
+                }
+            },
+            Command::UNREGISTER => {
+                //synchronized call, without response
+                if let Ok(usage) = _parse_body(codec, &req_data, flags).and_then(|(v,_)| _parse_usage(&v)) {
+                    if self.capability_set.contains(&usage) {
+                        if let Ok(mut ffi_obj) = ffi.lock() {
+                            let (ref name, ref sig) = usage;
+                            ffi_obj.unregister( name, sig );
+                            self.capability_set.remove(&usage);
+                        }
+                    }
+                }
+            },
+            Command::CALL => {
+                let request = _parse_body(codec, &req_data, flags).and_then(|(v, blobs)| {
+                    Ok(( _parse_descriptor(&v, blobs)?, _parse_deadline(&v)? ))
+                });
+                match request {
+                    Ok((descriptor, deadline)) => {
+                        let token = self.track(seq, deadline);
+                        if let Ok(ffi_obj) = ffi.lock() {
+                            ffi_obj.execute(descriptor, &token, self.answer_to(seq, _call_response));
+                        }
+                    },
+                    Err(e) => tx.send( (seq, 0, _error(codec, e)) )?
+                }
+            },
+            Command::ONE_WAY => {
+                //no response for one-way
+                if let Ok(descriptor) = _parse_body(codec, &req_data, flags).and_then(|(v, blobs)| _parse_descriptor(&v, blobs)) {
+                    if let Ok(ffi_obj) = ffi.lock() {
+                        ffi_obj.execute(descriptor, &CallToken::new(None), |_|{});
+                    }
+                }
+            },
+            Command::CHAIN_CALL => {
+                //responds with the last result, or with all the results in order if `"all"`
+                let request = _parse_body(codec, &req_data, flags).and_then(|(v, blobs)| {
+                    let all = v.get("all").and_then(|x| x.as_bool()).unwrap_or(false);
+                    Ok(( _parse_descriptors(&v, "sig_func_args_table", blobs)?, _parse_deadline(&v)?, all ))
+                });
+                match request {
+                    Ok((descriptors, deadline, all)) => {
+                        let token = self.track(seq, deadline);
+                        if let Ok(ffi_obj) = ffi.lock() {
+                            if all {
+                                ffi_obj.chain_execute_all(descriptors, &token, self.answer_to(seq, _batch_response));
+                            } else {
+                                ffi_obj.chain_execute(descriptors, &token, self.answer_to(seq, _call_response));
+                            }
+                        }
+                    },
+                    Err(e) => tx.send( (seq, 0, _error(codec, e)) )?
+                }
+            },
+            Command::BATCH => {
+                //the items are called independently, responds with all the results in order
+                let request = _parse_body(codec, &req_data, flags).and_then(|(v, blobs)| {
+                    Ok(( _parse_descriptors(&v, "batch", blobs)?, _parse_deadline(&v)? ))
+                });
+                match request {
+                    Ok((descriptors, deadline)) => {
+                        let token = self.track(seq, deadline);
+                        if let Ok(ffi_obj) = ffi.lock() {
+                            ffi_obj.batch_execute(descriptors, &token, self.answer_to(seq, _batch_response));
+                        }
+                    },
+                    Err(e) => tx.send( (seq, 0, _error(codec, e)) )?
+                }
+            },
+            Command::SUBSCRIBE => {
+                //synchronized call
+                match _parse_body(codec, &req_data, flags).and_then(|(v,_)| _parse_subscription(&v)) {
+                    Ok((name, event)) => {
+                        let id = self.attach_sink();
+                        self.events.subscribe(id, &name, &event);
+                        self.tx.send( (seq, 0, _response(codec, Ok(true))) )?;
+                    },
+                    Err(e) => self.tx.send( (seq, 0, _error(codec, e)) )?
+                }
+            },
+            Command::UNSUBSCRIBE => {
+                //synchronized call, responds whether subscribed before
+                match _parse_body(codec, &req_data, flags).and_then(|(v,_)| _parse_subscription(&v)) {
+                    Ok((name, event)) => {
+                        let existed = match self.sink_id {
+                            Some(id) => self.events.unsubscribe(id, &name, &event),
+                            None => false
+                        };
+                        tx.send( (seq, 0, _response(codec, Ok(existed))) )?;
+                    },
+                    Err(e) => tx.send( (seq, 0, _error(codec, e)) )?
+                }
+            },
+            Command::CANCEL => {
+                //synchronized call, responds whether the call was in-flight;
+                //the cancelled call itself is answered with `Cancelled`
+                match _parse_body(codec, &req_data, flags).and_then(|(v,_)| _parse_seq(&v)) {
+                    Ok(target) => {
+                        let token = self.inflight.lock().ok().and_then(|m| m.get(&target).cloned());
+                        if let Some(ref token) = token {
+                            token.cancel();
+                        }
+                        tx.send( (seq, 0, _response(codec, Ok(token.is_some()))) )?;
+                    },
+                    Err(e) => tx.send( (seq, 0, _error(codec, e)) )?
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Dispatcher {
+    fn drop(&mut self) {
+        // finalization after connection drop
+        let inflight:Vec<CallToken> = match self.inflight.lock() {
+            Ok(inflight) => inflight.values().cloned().collect(),
+            Err(_) => Vec::new()
+        };
+        inflight.iter().for_each(|token| token.cancel());
+        if let Some(id) = self.sink_id {
+            self.events.detach(id);
+        }
+        if let Ok(mut ffi_obj) = self.ffi.lock() {
+            for (name, sig) in &self.capability_set {
+                ffi_obj.unregister(name, sig);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::process::Command as Process;
+    //
+    use serde_ipc::FFIManager;
+    use vdm_capability_abi::ABI_VERSION;
+
+    fn _str(s:&str) -> Arg {
+        Arg::Str(s.into())
+    }
+
+    fn _code<T>(result:Result<T, IPCError>) -> ErrorCode {
+        result.err().map(|e| e.code).unwrap()
+    }
+
+    fn _root() -> PathBuf {
+        let root = std::env::temp_dir().join( format!("vdm-dispatch-{}", std::process::id()) );
+        std::fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    /// Install `tests/fixtures/capability.c` as capability `name`, declaring the functions in `funcs` toml.
+    fn _install_fixture(name:&str, funcs:&str) {
+        let lib = _root().join( format!("lib{}.so", name) );
+        let status = Process::new("cc").args( ["-shared", "-fPIC", "-o"] ).arg(&lib)
+            .arg( format!("-DVDM_ABI_VERSION={}", ABI_VERSION) )
+            .arg( concat!("-I", env!("CARGO_MANIFEST_DIR"), "/../../interface") )
+            .arg( concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/capability.c") )
+            .status().unwrap();
+        assert!( status.success() );
+        let config = format!("entry = {:?}\nfiles = []\n\n[metadata]\nname = {:?}\nclass = \"rust\"\nversion = \"0.1.0\"\n\n{}",
+            lib, name, funcs);
+        std::fs::write( _root().join(name), config ).unwrap();
+    }
+
+    /// A dispatcher over the capability root of the tests, with the receiver of its responses.
+    fn _dispatcher(config:IPCConfig) -> (Dispatcher, mpsc::Receiver<Message>) {
+        let ffi = FFIManager::new( _root() ).into_shared();
+        let (tx, rx) = mpsc::channel();
+        ( Dispatcher::new(ffi, tx, config, Codec::Json), rx )
+    }
+
+    fn _feed(dispatcher:&mut Dispatcher, seq:u32, command:u16, flags:u16, body:&[u8]) {
+        let header = ReqHeader{ seq, command, flags, size:body.len() as u32 };
+        dispatcher.feed(&header, body.to_vec()).unwrap();
+    }
+
+    /// Receive the next response as (seq, flags, envelope, blobs).
+    fn _reply(rx:&mpsc::Receiver<Message>) -> (u32, u16, Value, Vec<u8>) {
+        let (seq, flags, data) = rx.recv_timeout( Duration::from_secs(5) ).unwrap();
+        let (v, blobs) = _parse_body(Codec::Json, &data, flags).unwrap();
+        (seq, flags, v, blobs.to_vec())
+    }
+
+    fn _err_code(v:&Value) -> u64 {
+        v["err"]["code"].as_u64().unwrap()
+    }
+
+    const BYTES_FUNCS:&str = r#"
+[metadata.func.reverse]
+restype = "bytes"
+args = [["data", "bytes"]]
+
+[metadata.func.relay]
+restype = "bytes"
+args = [["name", "string"], ["text", "string"]]
+"#;
+
+    /// Register the capability through the dispatcher, and return its signature.
+    fn _register(dispatcher:&mut Dispatcher, rx:&mpsc::Receiver<Message>, name:&str) -> String {
+        let body = json!({ "name": name }).to_string();
+        _feed(dispatcher, 1, Command::REGISTER as u16, 0, body.as_bytes());
+        _reply(rx).2["ok"]["sig"].as_str().unwrap().to_string()
+    }
+
+    /// Build `[json_len | json | blobs]` body.
+    fn _binary_body(v:&Value, blobs:&[u8]) -> Vec<u8> {
+        let json = Codec::Json.encode(v).unwrap();
+        let mut body = (json.len() as u32).to_le_bytes().to_vec();
+        body.extend(json);
+        body.extend_from_slice(blobs);
+        body
+    }
+
+    #[test]
+    fn parses_call_body() {
+        let raw = Codec::Json.encode( &json!({
+            "sig": "42", "func": "add", "args": ["1", 2, 0.5, true, null, {"k": [1]}], "deadline": 500
+        }) ).unwrap();
+        let (v, blobs) = _parse_body(Codec::Json, &raw, 0).unwrap();
+        assert!( blobs.is_empty() );
+        let (sig, func, args) = _parse_descriptor(&v, blobs).unwrap();
+        assert_eq!( (sig.as_str(), func.as_str()), ("42", "add") );
+        assert_eq!( args, vec![_str("1"), _str("2"), _str("0.5"), _str("true"), _str("null"), _str(r#"{"k":[1]}"#)] );
+        assert_eq!( _parse_deadline(&v).unwrap(), Some(Duration::from_millis(500)) );
+    }
+
+    #[test]
+    fn parses_one_way_body() {
+        // the same descriptor without deadline, in the negotiated codec
+        let raw = Codec::MsgPack.encode( &json!({"sig": "42", "func": "log", "args": []}) ).unwrap();
+        let (v, blobs) = _parse_body(Codec::MsgPack, &raw, 0).unwrap();
+        let (_, func, args) = _parse_descriptor(&v, blobs).unwrap();
+        assert_eq!( (func.as_str(), args.len()), ("log", 0) );
+        assert_eq!( _parse_deadline(&v).unwrap(), None );
+        assert_eq!( _parse_deadline(&json!({"deadline": null})).unwrap(), None );
+    }
+
+    #[test]
+    fn parses_chain_call_body() {
+        let v = json!({
+            "sig_func_args_table": [["1", "f", ["x"]], ["2", "g", ["restype_1_f", 3]]],
+            "all": true
+        });
+        let descriptors = _parse_descriptors(&v, "sig_func_args_table", &[]).unwrap();
+        assert_eq!( descriptors, vec![
+            ("1".to_string(), "f".to_string(), vec![_str("x")]),
+            ("2".to_string(), "g".to_string(), vec![_str("restype_1_f"), _str("3")])
+        ] );
+        assert_eq!( _parse_descriptors(&json!({"sig_func_args_table": []}), "sig_func_args_table", &[]).unwrap(), vec![] );
+    }
+
+    #[test]
+    fn parses_blob_and_b64_args() {
+        let body = _binary_body( &json!({"args": [{"$blob": [1, 2]}, {"$b64": "AAH/"}, "s"]}), b"\x00\x01\x02" );
+        let (v, blobs) = _parse_body(Codec::Json, &body, FLAG_BINARY).unwrap();
+        assert_eq!( blobs, b"\x00\x01\x02" );
+        let args = _parse_args(&v["args"], blobs).unwrap();
+        assert_eq!( args, vec![Arg::Bytes(vec![1, 2]), Arg::Bytes(vec![0, 1, 255]), _str("s")] );
+    }
+
+    #[test]
+    fn rejects_malformed_bodies() {
+        assert_eq!( _code(_parse_body(Codec::Json, b"{\"sig\":", 0)), ErrorCode::MalformedRequest );
+        assert_eq!( _code(_parse_body(Codec::Json, b"\x01\x00", FLAG_BINARY)), ErrorCode::MalformedRequest );
+        assert_eq!( _code(_parse_body(Codec::Json, b"\xff\x00\x00\x00{}", FLAG_BINARY)), ErrorCode::MalformedRequest );
+
+        assert!( _parse_descriptor(&json!({"sig": "1", "args": []}), &[]).is_err() );
+        assert!( _parse_descriptor(&json!({"sig": "1", "func": "f", "args": "x"}), &[]).is_err() );
+        assert!( _parse_descriptor(&json!({"sig": 1, "func": "f", "args": []}), &[]).is_err() );
+        assert!( _parse_descriptors(&json!({"batch": [["1", "f"]]}), "batch", &[]).is_err() );
+        assert!( _parse_descriptors(&json!({"batch": {}}), "batch", &[]).is_err() );
+        assert!( _parse_descriptors(&json!({}), "batch", &[]).is_err() );
+
+        assert_eq!( _code(_parse_deadline(&json!({"deadline": -1}))), ErrorCode::MalformedRequest );
+        assert_eq!( _code(_parse_deadline(&json!({"deadline": "1s"}))), ErrorCode::MalformedRequest );
+        assert_eq!( _code(_parse_name(&json!({"name": 1}))), ErrorCode::MalformedRequest );
+        assert!( _parse_usage(&json!({"name": "calc"})).is_err() );
+        assert!( _parse_subscription(&json!({"name": "calc"})).is_err() );
+        assert!( _parse_seq(&json!({})).is_err() );
+    }
+
+    #[test]
+    fn wraps_results_into_envelopes() {
+        let mut blobs = Vec::new();
+        assert_eq!( _envelope(Ok(_str("3")), &mut blobs, true), json!({"ok": "3"}) );
+        assert_eq!( _envelope(Ok(Arg::Int(3)), &mut blobs, true), json!({"ok": 3}) );
+        assert_eq!( _envelope(Ok(Arg::Bool(false)), &mut blobs, true), json!({"ok": false}) );
+        let err = IPCError::new(ErrorCode::UnknownFunction, "'f' not declared in metadata.");
+        assert_eq!( _envelope(Err(err.clone()), &mut blobs, true),
+            json!({"err": {"code": 0x05, "message": "'f' not declared in metadata."}}) );
+        assert!( blobs.is_empty() );
+
+        let (flags, body) = _call_response(Codec::Json, Err(err), false);
+        assert_eq!( flags, 0 );
+        assert_eq!( Codec::Json.decode(&body).unwrap()["err"]["code"], json!(0x05) );
+        assert_eq!( Codec::Json.decode(&_response(Codec::Json, Ok(true))).unwrap(), json!({"ok": true}) );
+    }
+
+    #[test]
+    fn answers_unknown_command() {
+        let (mut dispatcher, rx) = _dispatcher( IPCConfig::default() );
+        _feed(&mut dispatcher, 7, 0x7f, 0, b"{}");
+        let (seq, _, v, _) = _reply(&rx);
+        assert_eq!( seq, 7 );
+        assert_eq!( _err_code(&v), ErrorCode::UnknownCommand as u64 );
+        assert_eq!( v["err"]["message"], json!("unknown command 0x7f.") );
+    }
+
+    #[test]
+    fn answers_malformed_body() {
+        let (mut dispatcher, rx) = _dispatcher( IPCConfig::default() );
+        _feed(&mut dispatcher, 1, Command::CALL as u16, 0, b"not json");
+        assert_eq!( _err_code(&_reply(&rx).2), ErrorCode::MalformedRequest as u64 );
+        _feed(&mut dispatcher, 2, Command::CHAIN_CALL as u16, 0, br#"{"sig_func_args_table": [["1"]]}"#);
+        assert_eq!( _err_code(&_reply(&rx).2), ErrorCode::MalformedRequest as u64 );
+        _feed(&mut dispatcher, 3, Command::CALL as u16, 0, br#"{"sig": "1", "func": "f", "args": [], "deadline": "soon"}"#);
+        assert_eq!( _err_code(&_reply(&rx).2), ErrorCode::MalformedRequest as u64 );
+    }
+
+    #[test]
+    fn answers_unknown_capability() {
+        let (mut dispatcher, rx) = _dispatcher( IPCConfig::default() );
+        _feed(&mut dispatcher, 1, Command::REGISTER as u16, 0, br#"{"name": "vdm-missing-capability"}"#);
+        let (seq, _, v, _) = _reply(&rx);
+        assert_eq!( seq, 1 );
+        assert_eq!( _err_code(&v), ErrorCode::UnknownCapability as u64 );
+        assert!( v.get("ok").is_none() );
+        // called by a signature never registered
+        _feed(&mut dispatcher, 2, Command::CALL as u16, 0, br#"{"sig": "1", "func": "f", "args": []}"#);
+        let (seq, _, v, _) = _reply(&rx);
+        assert_eq!( seq, 2 );
+        assert_eq!( _err_code(&v), ErrorCode::BadSignature as u64 );
+    }
+
+    #[test]
+    fn reassembles_chunked_requests() {
+        let (mut dispatcher, rx) = _dispatcher( IPCConfig::default() );
+        let body: &[u8] = br#"{"name": "vdm-missing-capability"}"#;
+        let register = Command::REGISTER as u16;
+        // interleaved chunks of two requests
+        _feed(&mut dispatcher, 1, register, FLAG_MORE, &body[..10]);
+        _feed(&mut dispatcher, 2, register, FLAG_MORE, &body[..20]);
+        _feed(&mut dispatcher, 1, register, FLAG_MORE, &body[10..20]);
+        assert!( rx.try_recv().is_err() );
+        _feed(&mut dispatcher, 2, register, 0, &body[20..]);
+        _feed(&mut dispatcher, 1, register, 0, &body[20..]);
+        for expected in &[2, 1] {
+            let (seq, _, v, _) = _reply(&rx);
+            assert_eq!( seq, *expected );
+            assert_eq!( _err_code(&v), ErrorCode::UnknownCapability as u64 );
+        }
+        assert!( dispatcher.pending.is_empty() );
+        assert_eq!( dispatcher.pending_size, 0 );
+    }
+
+    #[test]
+    fn rejects_oversized_request() {
+        let config = IPCConfig{ max_message_size: 64, ..IPCConfig::default() };
+        let (mut dispatcher, rx) = _dispatcher(config);
+        let alive = Command::ALIVE as u16;
+        _feed(&mut dispatcher, 1, alive, FLAG_MORE, &[b' '; 40]);
+        assert!( rx.try_recv().is_err() );
+        _feed(&mut dispatcher, 1, alive, FLAG_MORE, &[b' '; 40]);
+        let (seq, _, v, _) = _reply(&rx);
+        assert_eq!( seq, 1 );
+        assert_eq!( _err_code(&v), ErrorCode::PayloadTooLarge as u64 );
+        // the rest chunks are dropped without another answer
+        _feed(&mut dispatcher, 1, alive, 0, &[b' '; 40]);
+        assert!( rx.try_recv().is_err() );
+        assert_eq!( dispatcher.pending_size, 0 );
+        // a single frame over the limit
+        _feed(&mut dispatcher, 2, alive, 0, &[b' '; 65]);
+        assert_eq!( _err_code(&_reply(&rx).2), ErrorCode::PayloadTooLarge as u64 );
+        // the seq is usable again
+        _feed(&mut dispatcher, 1, alive, 0, b"");
+        assert!( _reply(&rx).2.get("ok").is_some() );
+    }
+
+    #[test]
+    fn caps_pending_requests() {
+        let config = IPCConfig{ max_message_size: 64, ..IPCConfig::default() };
+        let (mut dispatcher, rx) = _dispatcher(config);
+        let body: &[u8] = br#"{"name": "vdm-missing-capability"}"#;
+        let register = Command::REGISTER as u16;
+        // each request within the limit, but not both of them
+        _feed(&mut dispatcher, 1, register, FLAG_MORE, &body[..30]);
+        _feed(&mut dispatcher, 2, register, FLAG_MORE, &body[..30]);
+        assert!( rx.try_recv().is_err() );
+        _feed(&mut dispatcher, 3, register, FLAG_MORE, &body[..30]);
+        let (seq, _, v, _) = _reply(&rx);
+        assert_eq!( seq, 3 );
+        assert_eq!( _err_code(&v), ErrorCode::PayloadTooLarge as u64 );
+        assert_eq!( v["err"]["message"], json!("pending requests exceed 64 bytes.") );
+        _feed(&mut dispatcher, 3, register, 0, &body[30..]);
+        assert!( rx.try_recv().is_err() );
+        // the pending ones still complete
+        _feed(&mut dispatcher, 1, register, 0, &body[30..]);
+        _feed(&mut dispatcher, 2, register, 0, &body[30..]);
+        for expected in &[1, 2] {
+            let (seq, _, v, _) = _reply(&rx);
+            assert_eq!( seq, *expected );
+            assert_eq!( _err_code(&v), ErrorCode::UnknownCapability as u64 );
+        }
+        assert_eq!( dispatcher.pending_size, 0 );
+    }
+
+    #[test]
+    fn refuses_functions_not_exported() {
+        _install_fixture("vdm-fixture-unexported", r#"
+[metadata.func.reverse]
+restype = "bytes"
+args = [["data", "bytes"]]
+
+[metadata.func.missing]
+restype = "string"
+args = []
+"#);
+        let (mut dispatcher, rx) = _dispatcher( IPCConfig::default() );
+        _feed(&mut dispatcher, 1, Command::REGISTER as u16, 0, br#"{"name": "vdm-fixture-unexported"}"#);
+        let v = _reply(&rx).2;
+        assert_eq!( _err_code(&v), ErrorCode::UnknownCapability as u64 );
+        assert_eq!( v["err"]["message"],
+            json!("'vdm-fixture-unexported' load failed: 'missing' not exported by capability.") );
+    }
+
+    #[test]
+    fn passes_bytes_as_blobs() {
+        _install_fixture("vdm-fixture-blobs", BYTES_FUNCS);
+        _install_fixture("vdm-fixture-blobs-peer", BYTES_FUNCS);
+        let config = IPCConfig{ features: FEATURE_BINARY, ..IPCConfig::default() };
+        let (mut dispatcher, rx) = _dispatcher(config);
+        let sig = _register(&mut dispatcher, &rx, "vdm-fixture-blobs");
+        let call = Command::CALL as u16;
+
+        let body = _binary_body( &json!({"sig": sig, "func": "reverse", "args": [{"$blob": [1, 3]}]}), b"\x00\x01\x02\xff" );
+        _feed(&mut dispatcher, 2, call, FLAG_BINARY, &body);
+        let (seq, flags, v, blobs) = _reply(&rx);
+        assert_eq!( (seq, flags), (2, FLAG_BINARY) );
+        assert_eq!( v, json!({"ok": {"$blob": [0, 3]}}) );
+        assert_eq!( _parse_arg(&v["ok"], &blobs), Some(Arg::Bytes(vec![0xff, 0x02, 0x01])) );
+        // `$b64` arguments in plain body, answered in blobs as negotiated
+        let body = json!({"sig": sig, "func": "reverse", "args": [{"$b64": "AAH/"}]}).to_string();
+        _feed(&mut dispatcher, 3, call, 0, body.as_bytes());
+        let (_, flags, v, blobs) = _reply(&rx);
+        assert_eq!( flags, FLAG_BINARY );
+        assert_eq!( _parse_arg(&v["ok"], &blobs), Some(Arg::Bytes(vec![0xff, 0x01, 0x00])) );
+        // bytes result of the capability called through `execute_bytes`
+        let body = json!({"sig": sig, "func": "relay", "args": ["vdm-fixture-blobs-peer", "abc"]}).to_string();
+        _feed(&mut dispatcher, 4, call, 0, body.as_bytes());
+        let (_, flags, v, blobs) = _reply(&rx);
+        assert_eq!( flags, FLAG_BINARY );
+        assert_eq!( _parse_arg(&v["ok"], &blobs), Some(Arg::Bytes(b"cba".to_vec())) );
+    }
+
+    #[test]
+    fn passes_bytes_as_base64() {
+        _install_fixture("vdm-fixture-b64", BYTES_FUNCS);
+        _install_fixture("vdm-fixture-b64-peer", BYTES_FUNCS);
+        let features = IPCConfig::default().features & !FEATURE_BINARY;
+        let (mut dispatcher, rx) = _dispatcher( IPCConfig{ features, ..IPCConfig::default() } );
+        let sig = _register(&mut dispatcher, &rx, "vdm-fixture-b64");
+        let call = Command::CALL as u16;
+
+        let body = json!({"sig": sig, "func": "reverse", "args": [{"$b64": "AP8="}]}).to_string();
+        _feed(&mut dispatcher, 2, call, 0, body.as_bytes());
+        let (seq, flags, v, blobs) = _reply(&rx);
+        assert_eq!( (seq, flags), (2, 0) );
+        assert_eq!( v, json!({"ok": {"$b64": "/wA="}}) );
+        assert_eq!( _parse_arg(&v["ok"], &blobs), Some(Arg::Bytes(vec![0xff, 0x00])) );
+        let body = json!({"sig": sig, "func": "relay", "args": ["vdm-fixture-b64-peer", "abc"]}).to_string();
+        _feed(&mut dispatcher, 3, call, 0, body.as_bytes());
+        assert_eq!( _reply(&rx).2, json!({"ok": {"$b64": "Y2Jh"}}) );
+        // blobs refused unless negotiated
+        let body = _binary_body( &json!({"sig": sig, "func": "reverse", "args": [{"$blob": [0, 1]}]}), b"\x00" );
+        _feed(&mut dispatcher, 4, call, FLAG_BINARY, &body);
+        assert_eq!( _err_code(&_reply(&rx).2), ErrorCode::MalformedRequest as u64 );
+    }
+
+    #[test]
+    fn rejects_malformed_bytes_arguments() {
+        let config = IPCConfig{ features: FEATURE_BINARY, ..IPCConfig::default() };
+        let (mut dispatcher, rx) = _dispatcher(config);
+        let blobs = b"\x00\x01\x02\x03";
+        for (seq, arg) in [
+            json!({"$b64": "not base64!"}),
+            json!({"$b64": 1}),
+            json!({"$blob": [2, 3]}),               //length past the end
+            json!({"$blob": [5, 0]}),               //offset past the end
+            json!({"$blob": [u64::MAX, 2]}),        //overflowed range
+            json!({"$blob": [0]}),
+        ].iter().enumerate() {
+            let body = _binary_body( &json!({"sig": "1", "func": "reverse", "args": [arg]}), blobs );
+            _feed(&mut dispatcher, seq as u32, Command::CALL as u16, FLAG_BINARY, &body);
+            let (_, _, v, _) = _reply(&rx);
+            assert_eq!( _err_code(&v), ErrorCode::MalformedRequest as u64, "{}", arg );
+        }
+    }
+}
@@ -1,11 +1,17 @@
+mod dispatch;
+mod ring;
 mod shmem;
+mod sockstream;
+mod transport;
 mod shared_consts;
+#[cfg(test)]
+include!( concat!(env!("OUT_DIR"), "/libpython.rs") );
 use pyo3::prelude::*;
 // use pyo3::wrap_pyfunction;
 // use crate::shared_consts::VDM_CAPABILITY_DIR;
 
 use serde_ipc::JsonifyIPC;
-use shmem::ShMem;
+use transport::Transport;
 
 #[pymodule]
 fn capability_manager(_py:Python, m:&PyModule) -> PyResult<()> {
@@ -38,8 +44,7 @@ fn capability_manager(_py:Python, m:&PyModule) -> PyResult<()> {
     #[pyfn(m, "start_daemon")]
     fn start_daemon(_py: Python) -> PyResult<()> {
         let root = Some( String::from("~/.vdm/lib") );
-        let server_port = Some(42000);
-        let mut daemon = JsonifyIPC::<ShMem>::new(root, server_port);
+        let mut daemon = JsonifyIPC::<Transport>::new(root, None, None);
         daemon.start();
         Ok(())
     }
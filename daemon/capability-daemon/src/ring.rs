@@ -0,0 +1,240 @@
+use std::io;
+use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// Control block at the start of segment: `[head:8B | tail:8B | reserved]`.
+/// `head` and `tail` are monotonic byte counters owned by the consumer and the producer.
+pub const RING_CTRL_LEN:usize = 64;
+
+unsafe fn volatile_copy<T>(src: *const T, len: usize) -> Vec<T> {
+    (0..len).map(|i| std::ptr::read_volatile(src.add(i))).collect()
+}
+
+unsafe fn volatile_write<T: Copy>(dst: *mut T, src: &[T]) {
+    for (i, item) in src.iter().enumerate() {
+        std::ptr::write_volatile(dst.add(i), *item);
+    }
+}
+
+/// Single-producer single-consumer ring of frames over a shared memory segment.
+/// "sem_data" is posted once per published frame, "sem_space" whenever the consumer frees space.
+/// The counters are written by the peer as well, so any inconsistent value is reported as `None`/`false`,
+/// for the connection to be dropped.
+pub struct RingBuffer {
+    ptr: *mut u8,
+    capacity: usize,
+    sem_data: *mut libc::sem_t,
+    sem_space: *mut libc::sem_t
+}
+
+impl RingBuffer {
+    /// Return `None` if the segment has no room beyond the control block.
+    pub fn new(ptr:*mut u8, len:usize, sem_data:*mut libc::sem_t, sem_space:*mut libc::sem_t) -> Option<Self> {
+        match len.checked_sub(RING_CTRL_LEN) {
+            Some(capacity) if capacity > 0 => Some( RingBuffer{ ptr, capacity, sem_data, sem_space } ),
+            _ => None
+        }
+    }
+
+    /// The largest frame fitting in the ring.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn head(&self) -> &AtomicU64 {
+        unsafe{ &*(self.ptr as *const AtomicU64) }
+    }
+
+    fn tail(&self) -> &AtomicU64 {
+        unsafe{ &*(self.ptr.add(8) as *const AtomicU64) }
+    }
+
+    fn data(&self) -> *mut u8 {
+        unsafe{ self.ptr.add(RING_CTRL_LEN) }
+    }
+
+    /// Number of bytes published but not consumed, or `None` if the counters are inconsistent.
+    pub fn readable(&self) -> Option<usize> {
+        let head = self.head().load(Ordering::Relaxed);
+        let tail = self.tail().load(Ordering::Acquire);
+        tail.checked_sub(head)
+            .map(|used| used as usize)
+            .filter(|&used| used <= self.capacity)
+    }
+
+    /// Block until the next frame is published; return `Ok(false)` on timeout or signal.
+    pub fn wait_data(&self, timeout:Duration) -> io::Result<bool> {
+        let mut deadline = libc::timespec{ tv_sec:0, tv_nsec:0 };
+        unsafe{ libc::clock_gettime(libc::CLOCK_REALTIME, &mut deadline) };
+        let nsec = deadline.tv_nsec as u64 + timeout.subsec_nanos() as u64;
+        deadline.tv_sec += (timeout.as_secs() + nsec / 1_000_000_000) as libc::time_t;
+        deadline.tv_nsec = (nsec % 1_000_000_000) as libc::c_long;
+
+        if unsafe{ libc::sem_timedwait(self.sem_data, &deadline) } == 0 {
+            return Ok(true);
+        }
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::ETIMEDOUT) | Some(libc::EINTR) => Ok(false),
+            _ => Err(err)
+        }
+    }
+
+    /// Copy `len` bytes at `offset` from the read position;
+    /// return `None` if they are not all published.
+    pub fn peek(&self, offset:usize, len:usize) -> Option<Vec<u8>> {
+        if offset.checked_add(len)? > self.readable()? {
+            return None;
+        }
+        let head = self.head().load(Ordering::Relaxed);
+        let pos = ((head % self.capacity as u64) as usize + offset) % self.capacity;
+        let first = len.min(self.capacity - pos);
+        unsafe{
+            let mut buf = volatile_copy(self.data().add(pos), first);
+            buf.extend( volatile_copy(self.data(), len - first) );
+            Some(buf)
+        }
+    }
+
+    /// Advance the read position by `len` peeked bytes, and signal the freed space.
+    pub fn consume(&self, len:usize) {
+        let head = self.head().load(Ordering::Relaxed);
+        self.head().store(head.saturating_add(len as u64), Ordering::Release);
+        unsafe{ libc::sem_post(self.sem_space) };
+    }
+
+    /// Publish one frame, blocking until enough space is freed.
+    /// Return `false` if the frame never fits, the counters are inconsistent, or the wait is interrupted or stopped.
+    pub fn push(&self, frame:&[u8], stopped:&AtomicBool) -> bool {
+        if frame.len() > self.capacity {
+            return false;
+        }
+        let tail = self.tail().load(Ordering::Relaxed);
+        let next = match tail.checked_add(frame.len() as u64) {
+            Some(next) => next,
+            None => return false
+        };
+        loop {
+            let used = match tail.checked_sub( self.head().load(Ordering::Acquire) ) {
+                Some(used) if used <= self.capacity as u64 => used as usize,
+                _ => return false
+            };
+            if self.capacity - used >= frame.len() {
+                break;
+            }
+            if unsafe{ libc::sem_wait(self.sem_space) } != 0 || stopped.load(Ordering::SeqCst) {
+                return false;
+            }
+        }
+        // write with wrap-around, then publish
+        let pos = (tail % self.capacity as u64) as usize;
+        let first = frame.len().min(self.capacity - pos);
+        unsafe{
+            volatile_write(self.data().add(pos), &frame[..first]);
+            volatile_write(self.data(), &frame[first..]);
+        }
+        self.tail().store(next, Ordering::Release);
+        unsafe{ libc::sem_post(self.sem_data) >= 0 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A ring of `capacity` bytes over private memory, with unnamed semaphores.
+    struct TestRing {
+        _mem: Vec<u64>,
+        sems: Box<[libc::sem_t; 2]>,
+        ring: RingBuffer
+    }
+
+    impl TestRing {
+        fn new(capacity:usize) -> Self {
+            let len = RING_CTRL_LEN + capacity;
+            let mut mem = vec![0u64; len.div_ceil(8)];
+            let mut sems:Box<[libc::sem_t; 2]> = Box::new( unsafe{ std::mem::zeroed() } );
+            unsafe{
+                libc::sem_init(&mut sems[0], 0, 0);
+                libc::sem_init(&mut sems[1], 0, 0);
+            }
+            let ring = RingBuffer::new(mem.as_mut_ptr() as *mut u8, len, &mut sems[0], &mut sems[1]).unwrap();
+            TestRing{ _mem:mem, sems, ring }
+        }
+    }
+
+    impl Drop for TestRing {
+        fn drop(&mut self) {
+            unsafe{
+                libc::sem_destroy(&mut self.sems[0]);
+                libc::sem_destroy(&mut self.sems[1]);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_segment_without_data() {
+        let mut mem = vec![0u64; RING_CTRL_LEN / 8];
+        let ptr = mem.as_mut_ptr() as *mut u8;
+        let null = std::ptr::null_mut();
+        assert!( RingBuffer::new(ptr, RING_CTRL_LEN, null, null).is_none() );
+        assert!( RingBuffer::new(ptr, 8, null, null).is_none() );
+    }
+
+    #[test]
+    fn full_and_empty() {
+        let t = TestRing::new(16);
+        let running = AtomicBool::new(false);
+        assert_eq!( t.ring.readable(), Some(0) );
+        assert_eq!( t.ring.peek(0, 1), None );
+
+        assert!( t.ring.push(&[7u8; 16], &running) );
+        assert_eq!( t.ring.readable(), Some(16) );
+        assert_eq!( t.ring.peek(0, 16), Some(vec![7u8; 16]) );
+        assert_eq!( t.ring.peek(8, 9), None );
+        // full: the push waits for space, and gives up once stopped
+        let stopped = AtomicBool::new(true);
+        unsafe{ libc::sem_post(t.ring.sem_space) };
+        assert!( !t.ring.push(&[1], &stopped) );
+        assert!( !t.ring.push(&[0u8; 17], &running) );
+
+        t.ring.consume(16);
+        assert_eq!( t.ring.readable(), Some(0) );
+    }
+
+    #[test]
+    fn wraps_around() {
+        let t = TestRing::new(16);
+        let running = AtomicBool::new(false);
+        assert!( t.ring.push(&[0u8; 10], &running) );
+        t.ring.consume(10);
+
+        let frame:Vec<u8> = (1..=12).collect();
+        assert!( t.ring.push(&frame, &running) );
+        assert_eq!( t.ring.readable(), Some(12) );
+        assert_eq!( t.ring.peek(0, 12), Some(frame.clone()) );
+        assert_eq!( t.ring.peek(4, 8), Some(frame[4..].to_vec()) );
+        t.ring.consume(12);
+        assert_eq!( t.ring.readable(), Some(0) );
+    }
+
+    #[test]
+    fn rejects_inconsistent_counters() {
+        let t = TestRing::new(16);
+        let running = AtomicBool::new(false);
+        // head beyond tail
+        t.ring.head().store(8, Ordering::Relaxed);
+        assert_eq!( t.ring.readable(), None );
+        assert_eq!( t.ring.peek(0, 1), None );
+        assert!( !t.ring.push(&[1], &running) );
+        // more published than the ring holds
+        t.ring.head().store(0, Ordering::Relaxed);
+        t.ring.tail().store(17, Ordering::Relaxed);
+        assert_eq!( t.ring.readable(), None );
+        assert!( !t.ring.push(&[1], &running) );
+        // counter about to overflow
+        t.ring.head().store(u64::MAX, Ordering::Relaxed);
+        t.ring.tail().store(u64::MAX, Ordering::Relaxed);
+        assert!( !t.ring.push(&[1], &running) );
+    }
+}
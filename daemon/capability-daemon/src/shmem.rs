@@ -1,202 +1,199 @@
 use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use std::ffi::CString;
-use std::convert::TryFrom;
-use std::collections::BTreeSet;
 //
-use num_enum::TryFromPrimitive;
-use shared_memory::{Shmem, ShmemConf};
-use serde_json::{self, Value};
+use shared_memory::ShmemConf;
 use threadpool::ThreadPool;
 //
-use serde_ipc::{FFIDescriptor, ArcFFIManager};
-use serde_ipc::IPCProtocol;
+use serde_ipc::{ArcFFIManager, ErrorCode, IPCError, Codec, Compression};
+use serde_ipc::{IPCProtocol, IPCConfig, IPCStream, FEATURE_CHUNKING};
+//
+use crate::dispatch::{Message, Dispatcher, ReqHeader, ResHeader, FLAG_MORE, REQ_HEADER_LEN, RES_HEADER_LEN, _error, _compress};
+use crate::ring::RingBuffer;
+use crate::transport::{pool_alive, join_pool};
+
+/// Period to check the peer liveness while idle.
+const LIVENESS_POLL:Duration = Duration::from_secs(1);
 
-type Message = (u32, String);
+fn _open_sem(name:&str, create:bool, value:u32) -> Result<*mut libc::sem_t, String> {
+    let flags = if create { libc::O_CREAT|libc::O_EXCL } else { 0 };
+    CString::new( format!("/{}", name) )
+        .map_err(|_| format!("CString::new failed"))
+        .and_then(|sem_name| {
+            match unsafe { libc::sem_open(sem_name.as_ptr(), flags, 0o600, value) } {
+                i if i != libc::SEM_FAILED => Ok(i),
+                _ => Err( format!("sem open failed.") )
+            }
+        })
+}
 
-// const SHM_REQ_MAX_SIZE:usize = 10*1024; //10KB
-#[allow(dead_code)] //Rust lint open issue, #47133
-const SHM_RES_MAX_SIZE:usize = 1024*1024; //1MB
+fn _post_sem(name:&str) {
+    if let Ok(sem) = _open_sem(name, false, 0) {
+        unsafe{ libc::sem_post(sem) };
+        _close(sem);
+    }
+}
 
-#[derive(Debug, Eq, PartialEq, TryFromPrimitive)]
-#[repr(u16)]
-enum Command {
-    ALIVE       = 0x00,
-    REGISTER    = 0x01,
-    UNREGISTER  = 0x02,
-    CALL        = 0x03,
-    ONE_WAY     = 0x04,
-    CHAIN_CALL  = 0x05
+fn _unlink_sem(name:&str) {
+    if let Ok(sem_name) = CString::new( format!("/{}", name) ) {
+        unsafe{ libc::sem_unlink(sem_name.as_ptr()) };
+    }
 }
 
-#[repr(C,packed)]
-struct ReqHeader {
-    seq: u32,
-    command: u16,
-    size: u16
+/// The client id goes into shm and semaphore names, so only plain characters are accepted.
+fn _valid_uid(uid:&str) -> bool {
+    !uid.is_empty() && uid.bytes().all(|c| c.is_ascii_alphanumeric() || c==b'_' || c==b'-')
 }
 
-#[repr(C,packed)]
-struct ResHeader {
-    seq: u32,
-    size: u32
+/// Pin the peer process learned at handshake, so that a reused pid is not mistaken for it.
+fn _open_pidfd(pid:i32) -> Option<i32> {
+    match unsafe{ libc::syscall(libc::SYS_pidfd_open, pid, 0) } {
+        fd if fd >= 0 => Some(fd as i32),
+        _ => None
+    }
 }
 
-unsafe fn volatile_copy<T>(src: *const T, len: usize) -> Vec<T> {
-    let mut result = Vec::with_capacity(len);
-    let mut tmp_ptr = src; //copy
-    loop {
-        result.push( std::ptr::read_volatile(tmp_ptr) );
-        tmp_ptr = tmp_ptr.add(1);
-        if tmp_ptr >= src.add(len) {
-            break result
-        }
+/// The peer is alive if its pinned process has not exited, or else it sent any frame within `peer_timeout`.
+fn _peer_alive(pidfd:Option<i32>, config:&IPCConfig, last_seen:Instant) -> bool {
+    match pidfd {
+        Some(fd) => {
+            //the pidfd turns readable once the process exits
+            let mut pfd = libc::pollfd{ fd, events: libc::POLLIN, revents: 0 };
+            let ready = unsafe{ libc::poll(&mut pfd, 1, 0) };
+            ready <= 0
+        },
+        None => last_seen.elapsed() < config.peer_timeout
     }
 }
 
-fn _recv_loop(ffi: ArcFFIManager, tx: mpsc::Sender<Message>, req_id: String) {
-    let mut capability_set: BTreeSet<String> = BTreeSet::new();
-    let shm_req = match ShmemConf::new().flink( &req_id ).open() {
+fn _recv_loop(ffi: ArcFFIManager, tx: mpsc::Sender<Message>, uid: String, config: IPCConfig, stopped: Arc<AtomicBool>) {
+    let req_id = format!("{}_req", uid);
+    let mut shm_req = match ShmemConf::new().os_id( format!("/{}", req_id) ).open() {
         Ok(m) => m,
         Err(e) => {
-            eprintln!("Unable to create or open shmem flink: {}", e);
+            eprintln!("Unable to create or open shmem: {}", e);
             return;
         }
     };
-    let sem_req = CString::new( format!("/{}", req_id) )
-        .map_err(|_| format!("CString::new failed"))
-        .and_then(|sem_name| {
-            match unsafe { libc::sem_open(sem_name.as_ptr(), 0, 0o600, 1) } {
-                i if i != libc::SEM_FAILED => Ok(i),
-                _ => Err( format!("sem open failed.") )
-            }
-        }).unwrap();
+    // "sem_req" signals a request frame is ready; "sem_req_ack" signals space is freed
+    let sem_req = _open_sem(&req_id, false, 0);
+    let sem_req_ack = _open_sem(&format!("{}_ack", req_id), false, 0);
+    let ring = match (&sem_req, &sem_req_ack) {
+        (Ok(sem_req), Ok(sem_req_ack)) => RingBuffer::new(shm_req.as_ptr(), shm_req.len(), *sem_req, *sem_req_ack),
+        _ => None
+    };
+    let codec = Codec::from_features(config.features);
+    let mut dispatcher = Dispatcher::new(ffi, tx, config.clone(), codec);
+    let pidfd = config.peer_pid.and_then(_open_pidfd);
+    let mut last_seen = Instant::now();
 
-    let _result = || -> Result<(), Box<dyn std::error::Error>> {
-        loop {
-            // acquire the lock
-            if unsafe{ libc::sem_wait(sem_req) } != 0 {
-                break Ok(()) //connection drop happened
+    let _result = (|| -> Result<(), Box<dyn std::error::Error>> {
+        let ring = match ring {
+            Some(ring) => ring,
+            None => {
+                eprintln!("Unable to open request ring of '{}'.", uid);
+                return Ok(()) //connection drop
             }
-            // load data from shm_req
-            let raw_ptr = shm_req.as_ptr(); //FIXME: check alive before use
-            let req_header_len = std::mem::size_of::<ReqHeader>();
-            let req_header:ReqHeader = unsafe{
-                let _header = volatile_copy(raw_ptr, req_header_len);
-                std::mem::transmute( &_header.as_ptr() )
-            };
-            let req_data = unsafe{ volatile_copy(raw_ptr.add(req_header_len), req_header.size as usize) };
-            let req_data = String::from_utf8(req_data).unwrap(); //panic as you like
-            // release the lock
-            if unsafe{ libc::sem_post(sem_req) } < 0 {
-                break Ok(()) //connection drop happened
+        };
+        loop {
+            // wait until the next request frame is ready, checking the peer while idle
+            match ring.wait_data(LIVENESS_POLL) {
+                Ok(true) => last_seen = Instant::now(),
+                Ok(false) if _peer_alive(pidfd, &config, last_seen) && !stopped.load(Ordering::SeqCst) => continue,
+                _ => break Ok(()) //connection drop happened
             }
-            // match command with its response
-            if let Ok(command) = Command::try_from(req_header.command) {
-                match command {
-                    Command::ALIVE => {
-                        //synchronized call
-                        tx.send( (req_header.seq, String::new()) )?;
-                    },
-                    Command::REGISTER => {
-                        //synchronized call
-                        let v: Value = serde_json::from_slice(req_data.as_bytes()).unwrap(); //panic as you like
-                        if let Value::String(ref name) = v["name"] {
-                            if let Ok(mut ffi_obj) = ffi.lock() {
-                                if let Some(cid) = ffi_obj.register(name) {
-                                    capability_set.insert( cid.clone() );
-                                    tx.send( (req_header.seq, cid) )?;
-                                }
-                            }
-                        }
-                    },
-                    Command::UNREGISTER => {
-                        //synchronized call
-                        let v: Value = serde_json::from_slice(req_data.as_bytes()).unwrap(); //panic as you like
-                        if let Value::String(ref name) = v["name"] {
-                            if capability_set.contains(name) {
-                                if let Ok(mut ffi_obj) = ffi.lock() {
-                                    ffi_obj.unregister( name, name ); //FIXME:
-                                    capability_set.remove(name);
-                                }
-                            }
-                        }
-                    },
-                    Command::CALL => {
-                        let tx_ref = tx.clone();
-                        if let Ok(ffi_obj) = ffi.lock() {
-                            let req_data: FFIDescriptor = ("".into(), "".into(), Vec::new()); //FIXME:
-                            ffi_obj.execute(req_data, move |res| {
-                                tx_ref.send( (req_header.seq, res) ).unwrap_err();
-                            });
-                        }                        
-                    },
-                    Command::ONE_WAY => {
-                        if let Ok(ffi_obj) = ffi.lock() {
-                            // ffi_obj.execute(req_data, |_|{}); //no callback for one-way
-                        }
-                    },
-                    Command::CHAIN_CALL => {
-                        let tx_ref = tx.clone();
-                        if let Ok(ffi_obj) = ffi.lock() {
-                            // ffi_obj.chain_execute(req_data, move |res| {
-                            //     tx_ref.send( (req_header.seq, res) ).unwrap_err();
-                            // })
-                        }
-                    }
-                }
+            if stopped.load(Ordering::SeqCst) {
+                break Ok(())
             }
+            // load the frame from shm_req: [header | chunk]
+            let req_header = match ring.peek(0, REQ_HEADER_LEN) {
+                Some(buf) => ReqHeader::from_bytes(&buf),
+                None => break Ok(()) //corrupted ring
+            };
+            let frame_len = REQ_HEADER_LEN + req_header.size as usize;
+            let chunk = match ring.peek(REQ_HEADER_LEN, req_header.size as usize) {
+                Some(chunk) => chunk,
+                None => break Ok(()) //corrupted ring
+            };
+            // release the space to the client
+            ring.consume(frame_len);
+            dispatcher.feed(&req_header, chunk)?;
         }
-    };
-    // finalization after connection drop
-    if let Ok(mut ffi_obj) = ffi.lock() {
-        for name in &capability_set {
-            ffi_obj.unregister(name, name); //FIXME:
-        }
+    })();
+    // finalization after connection drop: release usages, unlink what the client left behind
+    stopped.store(true, Ordering::SeqCst);
+    _post_sem( &format!("{}_res_ack", uid) ); //wake up "send" thread
+    drop(dispatcher);
+    for sem in [sem_req, sem_req_ack].iter().flatten() {
+        _close(*sem);
+    }
+    if let Some(fd) = pidfd {
+        unsafe{ libc::close(fd) };
     }
-    _close(sem_req);
+    _unlink_sem(&req_id);
+    _unlink_sem(&format!("{}_ack", req_id));
+    shm_req.set_owner(true); //unlink on drop
 }
 
-fn _send_loop(rx: mpsc::Receiver<Message>, res_id: String) {
-    let shm_res = match ShmemConf::new().size(SHM_RES_MAX_SIZE).flink( &res_id ).create() {
+fn _send_loop(rx: mpsc::Receiver<Message>, res_id: String, config: IPCConfig, stopped: Arc<AtomicBool>) {
+    let shm_res = match ShmemConf::new().size(config.res_size).os_id( format!("/{}", res_id) ).create() {
         Ok(m) => m,
         Err(e) => {
-            eprintln!("Unable to create or open shmem flink: {}", e);
+            eprintln!("Unable to create or open shmem: {}", e);
             return;
         }
     };
 
-    let sem_res = CString::new( format!("/{}", res_id) )
-        .map_err(|_| format!("CString::new failed"))
-        .and_then(|sem_name| {
-            match unsafe { libc::sem_open(sem_name.as_ptr(), libc::O_CREAT|libc::O_EXCL, 0o600, 1) } {
-                i if i != libc::SEM_FAILED => Ok(i),
-                _ => Err( format!("sem open failed.") )
-            }
-        }).unwrap();
+    // "sem_res" signals a response frame is ready; "sem_res_ack" signals space is freed
+    let sem_res = _open_sem(&res_id, true, 0);
+    let sem_res_ack = _open_sem(&format!("{}_ack", res_id), true, 0);
+    let ring = match (&sem_res, &sem_res_ack) {
+        (Ok(sem_res), Ok(sem_res_ack)) => RingBuffer::new(shm_res.as_ptr(), shm_res.len(), *sem_res, *sem_res_ack),
+        _ => None
+    };
+    let codec = Codec::from_features(config.features);
+    let compression = Compression::from_features(config.features);
 
-    let _result = || -> Result<(), Box<dyn std::error::Error>> {
-        while let Ok(_message) = rx.recv() {
-            // acquire the lock
-            if unsafe{ libc::sem_wait(sem_res) } != 0 {
-                break;
+    let _result = (|| -> Result<(), Box<dyn std::error::Error>> {
+        let (ring, chunk_size) = match ring {
+            Some(ring) if ring.capacity() > RES_HEADER_LEN => {
+                let chunk_size = ring.capacity() - RES_HEADER_LEN;
+                (ring, chunk_size)
+            },
+            _ => {
+                eprintln!("Unable to open response ring '{}'.", res_id);
+                return Ok(())
             }
-            {
-                let (seq, data) = _message;
-                let data = data.as_bytes();
-                let size = data.len() as u32;
-                let buffer = unsafe{
-                    let res_header = ResHeader{seq, size};
-                };
-                //TODO: write volatile to shared memory
+        };
+        while let Ok(_message) = rx.recv() {
+            let (seq, mut flags, mut data) = _compress(compression, config.compress_threshold, _message);
+            if config.features & FEATURE_CHUNKING == 0 && data.len() > chunk_size {
+                flags = 0; //plain error body
+                data = _error( codec, IPCError::new(ErrorCode::PayloadTooLarge,
+                        format!("response exceeds {} bytes without chunking.", chunk_size)) );
             }
-            // release the lock
-            if unsafe{ libc::sem_post(sem_res) } < 0 {
-                break;
+            let data = data.as_slice();
+            // split into chunks fitting in the ring; at least one (maybe empty) chunk
+            let chunks:Vec<&[u8]> = if data.is_empty() { vec![data] } else { data.chunks(chunk_size).collect() };
+            let num_chunks = chunks.len();
+            for (i, chunk) in chunks.into_iter().enumerate() {
+                // publish the frame [header | chunk], waiting for space if the ring is full
+                let flags = if i+1 < num_chunks { flags | FLAG_MORE } else { flags };
+                let mut frame = ResHeader{ seq, flags, size:chunk.len() as u32 }.to_bytes().to_vec();
+                frame.extend_from_slice(chunk);
+                if !ring.push(&frame, &stopped) {
+                    return Ok(());
+                }
             }
         }
         Ok(())
-    };
-    _close(sem_res);
+    })();
+    for sem in [sem_res, sem_res_ack].iter().flatten() {
+        _close(*sem);
+    }
+    _unlink_sem(&res_id);
+    _unlink_sem(&format!("{}_ack", res_id));
 }
 
 fn _close(sem:*mut libc::sem_t) {
@@ -206,53 +203,96 @@ fn _close(sem:*mut libc::sem_t) {
 #[derive(Clone)]
 pub struct ShMem {
     uid: String,
+    config: IPCConfig,
     ffi: Option<ArcFFIManager>,
+    stopped: Arc<AtomicBool>,
     pool: Arc<Mutex<ThreadPool>>
 }
 
 impl IPCProtocol for ShMem {
-    type Message = (u32, String);
+    type Message = Message;
 
-    fn new(uid:String, ffi:ArcFFIManager) -> Self {
+    fn new(uid:String, config:IPCConfig, ffi:ArcFFIManager, _stream:Option<IPCStream>) -> Self {
         let ffi = Some(ffi);
+        let stopped = Arc::new(AtomicBool::new(false));
         let pool = Arc::new(Mutex::new(
             ThreadPool::new(2)
         ));
-        Self{ uid, ffi, pool }
+        Self{ uid, config, ffi, stopped, pool }
     }
 
     fn is_alive(&self) -> bool {
-        if let Ok(pool_obj) = self.pool.lock() {
-            pool_obj.active_count() < 2
-        }
-        else {
-            false
-        }
+        !self.stopped.load(Ordering::SeqCst) && pool_alive(&self.pool)
     }
 
     fn stop(&self) {
-        //stop when the pool is dropped.
+        self.stopped.store(true, Ordering::SeqCst);
+        if !_valid_uid(&self.uid) {
+            return;
+        }
+        // wake up the threads blocked on semaphores
+        _post_sem( &format!("{}_req", self.uid) );
+        _post_sem( &format!("{}_res_ack", self.uid) );
+    }
+
+    fn join(&self, timeout:Duration) -> bool {
+        join_pool(&self.pool, timeout)
     }
 
     fn spawn_send_thread(&mut self, rx: mpsc::Receiver<Self::Message>) {
+        if !_valid_uid(&self.uid) {
+            eprintln!("Connection rejected for client id {:?}.", self.uid);
+            self.stopped.store(true, Ordering::SeqCst);
+            return;
+        }
         let res_id = format!("{}_res", self.uid);
-        
+        let config = self.config.clone();
+        let stopped = self.stopped.clone();
+
         if let Ok(pool_obj) = self.pool.lock() {
             pool_obj.execute(move || {
-                _send_loop(rx, res_id);
+                _send_loop(rx, res_id, config, stopped);
             });
         }
     }
 
     fn spawn_recv_thread(&mut self, tx: mpsc::Sender<Self::Message>) {
+        if !_valid_uid(&self.uid) {
+            self.stopped.store(true, Ordering::SeqCst);
+            return;
+        }
         let ffi = self.ffi.clone();
-        let req_id = format!("{}_req", self.uid);
-        
+        let uid = self.uid.clone();
+        let config = self.config.clone();
+        let stopped = self.stopped.clone();
+
         if let Ok(pool_obj) = self.pool.lock() {
             pool_obj.execute(move || {
-                _recv_loop(ffi.unwrap(), tx, req_id);
+                _recv_loop(ffi.unwrap(), tx, uid, config, stopped);
             });
         }
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    #[test]
+    fn pidfd_tracks_peer_exit() {
+        let config = IPCConfig::default();
+        let mut child = Command::new("sleep").arg("0.2").spawn().unwrap();
+        let pidfd = _open_pidfd(child.id() as i32);
+        assert!( pidfd.is_some() );
+        assert!( _peer_alive(pidfd, &config, Instant::now()) );
+        child.wait().unwrap();
+        //the pid may be reused by now, while the pidfd still refers to the exited one
+        assert!( !_peer_alive(pidfd, &config, Instant::now()) );
+        unsafe{ libc::close(pidfd.unwrap()) };
+        // without a pidfd, only the frames tell
+        assert!( _peer_alive(None, &config, Instant::now()) );
+        assert!( !_peer_alive(None, &config, Instant::now() - config.peer_timeout) );
+    }
+}
@@ -0,0 +1,138 @@
+use std::io::{self, Read, Write};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+//
+use threadpool::ThreadPool;
+//
+use serde_ipc::{ArcFFIManager, IPCProtocol, IPCConfig, IPCStream, Codec, Compression};
+//
+use crate::dispatch::{Message, Dispatcher, ReqHeader, ResHeader, REQ_HEADER_LEN, RES_HEADER_LEN, _compress};
+use crate::transport::{pool_alive, join_pool};
+
+fn _recv_loop(ffi: ArcFFIManager, tx: mpsc::Sender<Message>, mut stream: IPCStream, config: IPCConfig) {
+    let codec = Codec::from_features(config.features);
+    let mut dispatcher = Dispatcher::new(ffi, tx, config.clone(), codec);
+
+    let _result = (|| -> Result<(), Box<dyn std::error::Error>> {
+        let mut buf = [0u8; REQ_HEADER_LEN];
+        loop {
+            // frame: [header | data], length-prefixed by `header.size`
+            if stream.read_exact(&mut buf).is_err() {
+                break Ok(()) //connection drop happened
+            }
+            let req_header = ReqHeader::from_bytes(&buf);
+            let size = req_header.size as usize;
+            if size > config.max_message_size {
+                // skip the payload to keep the stream in frame
+                io::copy( &mut (&mut stream).take(size as u64), &mut io::sink() )?;
+                dispatcher.reject(req_header.seq, dispatcher.too_large())?;
+                continue
+            }
+            let mut data = vec![0u8; size];
+            if stream.read_exact(&mut data).is_err() {
+                break Ok(()) //connection drop happened
+            }
+            dispatcher.feed(&req_header, data)?;
+        }
+    })();
+    // finalization after connection drop
+    drop(dispatcher);
+    stream.shutdown();
+}
+
+fn _send_loop(rx: mpsc::Receiver<Message>, mut stream: IPCStream, config: IPCConfig) {
+    let compression = Compression::from_features(config.features);
+    while let Ok(_message) = rx.recv() {
+        let (seq, flags, data) = _compress(compression, config.compress_threshold, _message);
+        let res_header = ResHeader{ seq, flags, size:data.len() as u32 }.to_bytes();
+        let mut frame = Vec::with_capacity(RES_HEADER_LEN + data.len());
+        frame.extend_from_slice(&res_header);
+        frame.extend_from_slice(&data);
+        if stream.write_all(&frame).is_err() {
+            break; //connection drop happened
+        }
+    }
+    stream.shutdown();
+}
+
+/// Frame requests and responses directly over the handshake socket.
+#[derive(Clone)]
+pub struct SockStream {
+    uid: String,
+    config: IPCConfig,
+    ffi: Option<ArcFFIManager>,
+    stream: Arc<Mutex<Option<IPCStream>>>,
+    pool: Arc<Mutex<ThreadPool>>
+}
+
+impl SockStream {
+    fn clone_stream(&self) -> Option<IPCStream> {
+        let stream = self.stream.lock().ok()?;
+        stream.as_ref()?.try_clone().ok()
+    }
+}
+
+impl IPCProtocol for SockStream {
+    type Message = Message;
+
+    fn new(uid:String, config:IPCConfig, ffi:ArcFFIManager, stream:Option<IPCStream>) -> Self {
+        let ffi = Some(ffi);
+        let stream = Arc::new(Mutex::new( stream ));
+        let pool = Arc::new(Mutex::new(
+            ThreadPool::new(2)
+        ));
+        Self{ uid, config, ffi, stream, pool }
+    }
+
+    fn is_alive(&self) -> bool {
+        pool_alive(&self.pool)
+    }
+
+    fn stop(&self) {
+        if let Ok(stream) = self.stream.lock() {
+            if let Some(ref stream) = *stream {
+                stream.shutdown(); //both threads exit on socket error
+            }
+        }
+    }
+
+    fn join(&self, timeout:Duration) -> bool {
+        join_pool(&self.pool, timeout)
+    }
+
+    fn spawn_send_thread(&mut self, rx: mpsc::Receiver<Self::Message>) {
+        let stream = match self.clone_stream() {
+            Some(stream) => stream,
+            None => {
+                eprintln!("{}: socket unavailable for sending.", self.uid);
+                return;
+            }
+        };
+
+        let config = self.config.clone();
+        if let Ok(pool_obj) = self.pool.lock() {
+            pool_obj.execute(move || {
+                _send_loop(rx, stream, config);
+            });
+        }
+    }
+
+    fn spawn_recv_thread(&mut self, tx: mpsc::Sender<Self::Message>) {
+        let ffi = self.ffi.clone();
+        let config = self.config.clone();
+        let stream = match self.clone_stream() {
+            Some(stream) => stream,
+            None => {
+                eprintln!("{}: socket unavailable for receiving.", self.uid);
+                return;
+            }
+        };
+
+        if let Ok(pool_obj) = self.pool.lock() {
+            pool_obj.execute(move || {
+                _recv_loop(ffi.unwrap(), tx, stream, config);
+            });
+        }
+    }
+
+}
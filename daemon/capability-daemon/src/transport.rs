@@ -0,0 +1,86 @@
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+//
+use threadpool::ThreadPool;
+use serde_ipc::{ArcFFIManager, IPCProtocol, IPCConfig, IPCStream};
+//
+use crate::dispatch::Message;
+use crate::shmem::ShMem;
+use crate::sockstream::SockStream;
+
+/// Whether both "send" and "recv" threads are still queued or running.
+pub fn pool_alive(pool:&Arc<Mutex<ThreadPool>>) -> bool {
+    if let Ok(pool_obj) = pool.lock() {
+        pool_obj.queued_count() + pool_obj.active_count() == 2
+    }
+    else {
+        false
+    }
+}
+
+/// Wait for all the threads in the pool to exit, return `false` on timeout.
+pub fn join_pool(pool:&Arc<Mutex<ThreadPool>>, timeout:Duration) -> bool {
+    let pool_obj = match pool.lock() {
+        Ok(pool_obj) => pool_obj.clone(),
+        Err(_) => return false
+    };
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        pool_obj.join();
+        tx.send(()).unwrap_or(());
+    });
+    rx.recv_timeout(timeout).is_ok()
+}
+
+/// The transport of one connection, selected during handshake.
+#[derive(Clone)]
+pub enum Transport {
+    ShMem(ShMem),
+    Socket(SockStream)
+}
+
+impl IPCProtocol for Transport {
+    type Message = Message;
+
+    fn new(uid:String, config:IPCConfig, ffi:ArcFFIManager, stream:Option<IPCStream>) -> Self {
+        match stream {
+            Some(_) => Transport::Socket( SockStream::new(uid, config, ffi, stream) ),
+            None => Transport::ShMem( ShMem::new(uid, config, ffi, None) )
+        }
+    }
+
+    fn is_alive(&self) -> bool {
+        match self {
+            Self::ShMem(p) => p.is_alive(),
+            Self::Socket(p) => p.is_alive()
+        }
+    }
+
+    fn stop(&self) {
+        match self {
+            Self::ShMem(p) => p.stop(),
+            Self::Socket(p) => p.stop()
+        }
+    }
+
+    fn join(&self, timeout:Duration) -> bool {
+        match self {
+            Self::ShMem(p) => p.join(timeout),
+            Self::Socket(p) => p.join(timeout)
+        }
+    }
+
+    fn spawn_send_thread(&mut self, rx: mpsc::Receiver<Self::Message>) {
+        match self {
+            Self::ShMem(p) => p.spawn_send_thread(rx),
+            Self::Socket(p) => p.spawn_send_thread(rx)
+        }
+    }
+
+    fn spawn_recv_thread(&mut self, tx: mpsc::Sender<Self::Message>) {
+        match self {
+            Self::ShMem(p) => p.spawn_recv_thread(tx),
+            Self::Socket(p) => p.spawn_recv_thread(tx)
+        }
+    }
+}
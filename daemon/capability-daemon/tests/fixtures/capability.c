@@ -0,0 +1,88 @@
+/* A rust-class capability exporting `vdm_capability_entry` by hand, for the dispatch tests:
+ * - `reverse(data: bytes) -> bytes` reverses the bytes;
+ * - `relay(name: string, text: string) -> bytes` calls `reverse` of capability `name` through `execute_bytes`.
+ * Nothing else is exported. */
+#include <stdlib.h>
+#include <string.h>
+#include "src_api.h"
+
+typedef struct {
+    uint32_t abi_version;
+    int (*call)(const char *func, const vdm_bytes_t *args, size_t argc, vdm_bytes_t *res);
+    void (*free_bytes)(vdm_bytes_t data);
+    bool (*has)(const char *func);
+} vdm_capability_t;
+
+static const vdm_host_t *HOST;
+
+void vdm_set_host(const vdm_host_t *host) {
+    HOST = host;
+}
+
+static vdm_bytes_t _copy(const uint8_t *data, size_t len) {
+    uint8_t *ptr = malloc(len + 1);
+    memcpy(ptr, data, len);
+    ptr[len] = 0;
+    return (vdm_bytes_t){ ptr, len };
+}
+
+static int _fail(const char *msg, vdm_bytes_t *res) {
+    *res = _copy((const uint8_t *)msg, strlen(msg));
+    return 1;
+}
+
+static bool has(const char *func) {
+    return func && (strcmp(func, "reverse") == 0 || strcmp(func, "relay") == 0);
+}
+
+static int _reverse(const vdm_bytes_t *data, vdm_bytes_t *res) {
+    *res = _copy(data->ptr, data->len);
+    uint8_t *ptr = (uint8_t *)res->ptr;
+    for (size_t i = 0; i < data->len; i++)
+        ptr[i] = data->ptr[data->len - 1 - i];
+    return 0;
+}
+
+static int _relay(const vdm_bytes_t *args, vdm_bytes_t *res) {
+    /* the arguments are not NUL-terminated */
+    vdm_bytes_t name = _copy(args[0].ptr, args[0].len), text = _copy(args[1].ptr, args[1].len);
+    const char *argv[] = { (const char *)text.ptr };
+    char *err = NULL;
+    vdm_bytes_t data = { NULL, 0 };
+    char *sig = HOST ? HOST->register_capability(HOST->ctx, (const char *)name.ptr, &err) : NULL;
+    int code = 1;
+    if (sig && HOST->execute_bytes(HOST->ctx, sig, "reverse", argv, 1, &data, &err) == 0) {
+        *res = _copy(data.ptr, data.len);
+        HOST->free_bytes(data);
+        code = 0;
+    } else {
+        _fail(err ? err : "no host.", res);
+    }
+    if (sig) {
+        HOST->unregister_capability(HOST->ctx, sig);
+        HOST->free_str(sig);
+    }
+    if (err)
+        HOST->free_str(err);
+    free((void *)name.ptr);
+    free((void *)text.ptr);
+    return code;
+}
+
+static int call(const char *func, const vdm_bytes_t *args, size_t argc, vdm_bytes_t *res) {
+    if (func && strcmp(func, "reverse") == 0 && argc == 1)
+        return _reverse(args, res);
+    if (func && strcmp(func, "relay") == 0 && argc == 2)
+        return _relay(args, res);
+    return _fail("bad call.", res);
+}
+
+static void free_bytes(vdm_bytes_t data) {
+    free((void *)data.ptr);
+}
+
+static const vdm_capability_t CAPABILITY = { VDM_ABI_VERSION, call, free_bytes, has };
+
+const vdm_capability_t *vdm_capability_entry(void) {
+    return &CAPABILITY;
+}
@@ -0,0 +1,229 @@
+//! Export the public functions of an inline module as a Rust capability,
+//! through the stable `vdm_capability_entry` ABI instead of Rust types across `extern fn`.
+//!
+//! ```ignore
+//! #[vdm_capability_macros::capability]
+//! mod calculator {
+//!     pub fn add(a:i64, b:i64) -> i64 { a + b }
+//!     pub fn greet(name:&str) -> Result<String, String> { Ok( format!("hello, {}", name) ) }
+//! }
+//! ```
+//!
+//! The arguments and results may be `String`/`&str`, `Vec<u8>`/`&[u8]`, `i64`, `f64` or `bool`,
+//! as declared by `string`, `bytes`, `int`, `float` and `bool` in metadata;
+//! a `Result` returns the error message to the caller.
+//!
+//! The generated code uses the vtable of `vdm-capability-abi`, which the capability crate depends on as well.
+//!
+//! Only one `#[capability]` module is allowed per crate, as it exports the `vdm_capability_entry` of the library:
+//!
+//! ```compile_fail,E0428
+//! #[vdm_capability_macros::capability]
+//! mod calculator {
+//!     pub fn add(a:i64, b:i64) -> i64 { a + b }
+//! }
+//!
+//! #[vdm_capability_macros::capability]
+//! mod greeter {
+//!     pub fn greet(name:&str) -> String { format!("hello, {}", name) }
+//! }
+//! # fn main() {}
+//! ```
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{quote, format_ident};
+use syn::{parse_macro_input, Item, ItemMod, FnArg, Pat, Type, Visibility};
+
+/// Return the owned type to convert the argument into, and how to pass it on.
+fn _arg_binding(ty:&Type, value:&syn::Ident) -> (TokenStream2, TokenStream2) {
+    if let Type::Reference(r) = ty {
+        match &*r.elem {
+            Type::Path(p) if p.path.is_ident("str") => return ( quote!(String), quote!(&#value) ),
+            Type::Slice(_) => return ( quote!(Vec<u8>), quote!(&#value) ),
+            _ => {}
+        }
+    }
+    ( quote!(#ty), quote!(#value) )
+}
+
+#[proc_macro_attribute]
+pub fn capability(_attr:TokenStream, item:TokenStream) -> TokenStream {
+    let module = parse_macro_input!(item as ItemMod);
+    let mod_name = &module.ident;
+    let items = match module.content {
+        Some((_, ref items)) => items,
+        None => {
+            return syn::Error::new_spanned(&module, "#[capability] takes an inline module.")
+                .to_compile_error().into();
+        }
+    };
+
+    let mut arms = Vec::new();
+    let mut names = Vec::new();
+    for item in items {
+        let func = match item {
+            Item::Fn(func) if matches!(func.vis, Visibility::Public(_)) => func,
+            _ => continue
+        };
+        let name = &func.sig.ident;
+        let name_bytes = syn::LitByteStr::new(name.to_string().as_bytes(), name.span());
+        let argc = func.sig.inputs.len();
+        names.push( name_bytes.clone() );
+
+        let mut bindings = Vec::new();
+        let mut passed = Vec::new();
+        for (i, input) in func.sig.inputs.iter().enumerate() {
+            let ty = match input {
+                FnArg::Typed(arg) if matches!(*arg.pat, Pat::Ident(_)) => &arg.ty,
+                _ => {
+                    return syn::Error::new_spanned(input, "capability functions take plain named arguments.")
+                        .to_compile_error().into();
+                }
+            };
+            let value = format_ident!("_arg{}", i);
+            let (owned, pass) = _arg_binding(ty, &value);
+            bindings.push( quote!( let #value = <#owned as FromArg>::from_arg(args[#i])?; ) );
+            passed.push(pass);
+        }
+
+        arms.push( quote! {
+            #name_bytes => {
+                if args.len() != #argc {
+                    return Err( format!("'{}' takes {} argument(s) but {} given.", stringify!(#name), #argc, args.len()) );
+                }
+                #(#bindings)*
+                IntoRes::into_res( super::#mod_name::#name( #(#passed),* ) )
+            }
+        } );
+    }
+
+    let entry_mod = format_ident!("__vdm_capability_{}", mod_name);
+    let expanded = quote! {
+        #module
+
+        #[doc(hidden)]
+        #[allow(non_snake_case)]
+        pub mod #entry_mod {
+            use std::os::raw::{c_char, c_int};
+            use std::ffi::CStr;
+            use ::vdm_capability_abi::{VdmBytes, VdmCapability, ABI_VERSION};
+
+            trait FromArg: Sized {
+                fn from_arg(arg:&[u8]) -> Result<Self, String>;
+            }
+
+            impl FromArg for Vec<u8> {
+                fn from_arg(arg:&[u8]) -> Result<Self, String> { Ok( arg.to_vec() ) }
+            }
+
+            impl FromArg for String {
+                fn from_arg(arg:&[u8]) -> Result<Self, String> {
+                    String::from_utf8( arg.to_vec() ).map_err(|e| e.to_string())
+                }
+            }
+
+            macro_rules! from_text {
+                ($($t:ty),*) => {$(
+                    impl FromArg for $t {
+                        fn from_arg(arg:&[u8]) -> Result<Self, String> {
+                            let text = String::from_arg(arg)?;
+                            text.trim().parse().map_err(|_| format!("'{}' is not {}.", text, stringify!($t)))
+                        }
+                    }
+                )*}
+            }
+            from_text!(i64, f64, bool);
+
+            trait IntoRes {
+                fn into_res(self) -> Result<Vec<u8>, String>;
+            }
+
+            impl IntoRes for Vec<u8> {
+                fn into_res(self) -> Result<Vec<u8>, String> { Ok(self) }
+            }
+
+            impl IntoRes for String {
+                fn into_res(self) -> Result<Vec<u8>, String> { Ok( self.into_bytes() ) }
+            }
+
+            impl IntoRes for &str {
+                fn into_res(self) -> Result<Vec<u8>, String> { Ok( self.as_bytes().to_vec() ) }
+            }
+
+            macro_rules! into_text {
+                ($($t:ty),*) => {$(
+                    impl IntoRes for $t {
+                        fn into_res(self) -> Result<Vec<u8>, String> { Ok( self.to_string().into_bytes() ) }
+                    }
+                )*}
+            }
+            into_text!(i64, f64, bool);
+
+            impl<T:IntoRes, E:std::fmt::Display> IntoRes for Result<T, E> {
+                fn into_res(self) -> Result<Vec<u8>, String> {
+                    self.map_err(|e| e.to_string()).and_then(IntoRes::into_res)
+                }
+            }
+
+            // allocated here, and released by `free_bytes` with the same allocator
+            fn _into_bytes(data:Vec<u8>) -> VdmBytes {
+                let data = data.into_boxed_slice();
+                let len = data.len();
+                VdmBytes{ ptr:Box::into_raw(data) as *const u8, len }
+            }
+
+            fn _dispatch(func:&[u8], args:&[&[u8]]) -> Result<Vec<u8>, String> {
+                match func {
+                    #(#arms),*
+                    _ => Err( format!("'{}' not found in library.", String::from_utf8_lossy(func)) )
+                }
+            }
+
+            extern "C" fn call(func:*const c_char, args:*const VdmBytes, argc:usize, res:*mut VdmBytes) -> c_int {
+                if func.is_null() || res.is_null() || (argc > 0 && args.is_null()) {
+                    return -1;
+                }
+                let func = unsafe{ CStr::from_ptr(func) }.to_bytes();
+                let args = if argc == 0 { &[][..] } else { unsafe{ std::slice::from_raw_parts(args, argc) } };
+                let args:Vec<&[u8]> = args.iter().map(|arg| {
+                    if arg.ptr.is_null() { &[][..] } else { unsafe{ std::slice::from_raw_parts(arg.ptr, arg.len) } }
+                }).collect();
+                // never unwind across the boundary
+                let result = std::panic::catch_unwind(|| _dispatch(func, &args))
+                    .unwrap_or_else(|_| Err( "capability panicked.".to_string() ));
+                let (code, data) = match result {
+                    Ok(data) => (0, data),
+                    Err(e) => (1, e.into_bytes())
+                };
+                unsafe{ *res = _into_bytes(data) };
+                code
+            }
+
+            extern "C" fn free_bytes(data:VdmBytes) {
+                if !data.ptr.is_null() {
+                    let data = std::ptr::slice_from_raw_parts_mut(data.ptr as *mut u8, data.len);
+                    unsafe{ drop(Box::from_raw(data)) };
+                }
+            }
+
+            extern "C" fn has(func:*const c_char) -> bool {
+                const NAMES:&[&[u8]] = &[ #(#names),* ];
+                !func.is_null() && NAMES.contains( &unsafe{ CStr::from_ptr(func) }.to_bytes() )
+            }
+
+            static CAPABILITY:VdmCapability = VdmCapability{ abi_version:ABI_VERSION, call, free_bytes, has };
+
+            // defined again by a second `#[capability]` in the crate, refused as E0428 before the symbols clash
+            #[macro_export]
+            macro_rules! __vdm_capability_defined_once_per_crate { () => {} }
+
+            #[no_mangle]
+            pub extern "C" fn vdm_capability_entry() -> *const VdmCapability {
+                &CAPABILITY
+            }
+        }
+    };
+    expanded.into()
+}
@@ -0,0 +1,58 @@
+use std::ffi::CString;
+//
+use vdm_capability_abi::{VdmBytes, VdmCapability, ABI_VERSION};
+
+#[vdm_capability_macros::capability]
+mod calculator {
+    pub fn add(a:i64, b:i64) -> i64 { a + b }
+    pub fn scale(x:f64, enabled:bool) -> f64 { if enabled { x * 2.0 } else { x } }
+    pub fn greet(name:&str) -> Result<String, String> {
+        if name.is_empty() { Err( "no name.".into() ) } else { Ok( format!("hello, {}", name) ) }
+    }
+    pub fn reverse(data:&[u8]) -> Vec<u8> { data.iter().rev().cloned().collect() }
+    #[allow(dead_code)]
+    fn hidden() {}
+}
+
+/// Call the function through the exported vtable, like the daemon does.
+fn call(func:&str, args:&[&[u8]]) -> (i32, Vec<u8>) {
+    let capability:&VdmCapability = unsafe{ &*__vdm_capability_calculator::vdm_capability_entry() };
+    let func = CString::new(func).unwrap();
+    let args:Vec<VdmBytes> = args.iter().map(|arg| VdmBytes{ ptr:arg.as_ptr(), len:arg.len() }).collect();
+    let mut res = VdmBytes{ ptr:std::ptr::null(), len:0 };
+    let code = (capability.call)(func.as_ptr(), args.as_ptr(), args.len(), &mut res);
+    let data = unsafe{ std::slice::from_raw_parts(res.ptr, res.len) }.to_vec();
+    (capability.free_bytes)(res);
+    (code, data)
+}
+
+#[test]
+fn exports_the_shared_abi_version() {
+    let capability = unsafe{ &*__vdm_capability_calculator::vdm_capability_entry() };
+    assert_eq!( capability.abi_version, ABI_VERSION );
+}
+
+#[test]
+fn tells_exported_functions() {
+    let capability = unsafe{ &*__vdm_capability_calculator::vdm_capability_entry() };
+    let has = |func:&str| (capability.has)( CString::new(func).unwrap().as_ptr() );
+    assert!( has("add") && has("reverse") );
+    assert!( !has("hidden") && !has("missing") );
+    assert!( !(capability.has)(std::ptr::null()) );
+}
+
+#[test]
+fn converts_arguments_and_results() {
+    assert_eq!( call("add", &[b"1", b" 2"]), (0, b"3".to_vec()) );
+    assert_eq!( call("scale", &[b"1.5", b"true"]), (0, b"3".to_vec()) );
+    assert_eq!( call("greet", &[b"vdm"]), (0, b"hello, vdm".to_vec()) );
+    assert_eq!( call("reverse", &[b"\x00\x01\xff"]), (0, b"\xff\x01\x00".to_vec()) );
+}
+
+#[test]
+fn reports_errors() {
+    assert_eq!( call("greet", &[b""]), (1, b"no name.".to_vec()) );
+    assert_eq!( call("add", &[b"1"]).0, 1 );
+    assert_eq!( call("add", &[b"one", b"2"]), (1, b"'one' is not i64.".to_vec()) );
+    assert_eq!( call("hidden", &[]).0, 1 );
+}
@@ -0,0 +1,256 @@
+use std::thread;
+use std::cmp::Reverse;
+use std::time::{Duration, Instant};
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::{Arc, Weak, Mutex, Condvar, OnceLock, PoisonError};
+use std::sync::atomic::{AtomicUsize, Ordering};
+//
+use threadpool::ThreadPool;
+//
+use crate::core::error::{ErrorCode, IPCError, IPCResult};
+
+type Answer = Box<dyn FnOnce(IPCResult) + Send>;
+type Interrupt = Box<dyn Fn() + Send>;
+
+/// The worker pool, growing by one for each abandoned job until it returns,
+/// up to twice its size while abandoned jobs hang.
+#[derive(Clone)]
+pub struct Workers {
+    pool: ThreadPool,
+    size: usize,
+    abandoned: Arc<AtomicUsize>
+}
+
+impl Workers {
+    pub fn new(size:usize) -> Self {
+        Workers{ pool:ThreadPool::new(size), size, abandoned:Arc::new(AtomicUsize::new(0)) }
+    }
+
+    pub fn execute<F>(&self, job:F)
+    where F: FnOnce() + Send + 'static
+    {
+        self.pool.execute(job);
+    }
+
+    fn resize(&self, abandoned:usize) {
+        let size = self.size + abandoned.min(self.size);
+        self.pool.clone().set_num_threads(size.max(1)); //clones share the same pool
+    }
+
+    fn grow(&self, n:usize) {
+        self.resize( self.abandoned.fetch_add(n, Ordering::SeqCst) + n );
+    }
+
+    fn shrink(&self) {
+        self.resize( self.abandoned.fetch_sub(1, Ordering::SeqCst).saturating_sub(1) );
+    }
+}
+
+#[derive(Default)]
+struct TokenState {
+    answer: Option<Answer>,
+    answered: bool,
+    running: usize,
+    aborted: bool,
+    interrupts: Vec<Interrupt>,
+    workers: Option<Workers>,
+    // the entry in `Deadlines::tokens`, if armed with a deadline
+    deadline_id: Option<u64>
+}
+
+#[derive(Default)]
+struct Deadlines {
+    next_id: u64,
+    queue: BinaryHeap<Reverse<(Instant, u64)>>,
+    tokens: HashMap<u64, Weak<Mutex<TokenState>>>
+}
+
+/// Stop watching the deadline of an answered call; its queue entry is skipped once due.
+fn _forget_deadline(id:Option<u64>) {
+    if let Some(id) = id {
+        let (ref lock, _) = *_deadlines();
+        lock.lock().unwrap_or_else(PoisonError::into_inner).tokens.remove(&id);
+    }
+}
+
+/// The deadlines of the armed tokens, all watched by one thread.
+fn _deadlines() -> &'static (Mutex<Deadlines>, Condvar) {
+    static DEADLINES:OnceLock<(Mutex<Deadlines>, Condvar)> = OnceLock::new();
+    DEADLINES.get_or_init(|| {
+        thread::spawn(_watch_deadlines);
+        Default::default()
+    })
+}
+
+/// Abort the calls past their deadline, skipping those answered and dropped meanwhile.
+fn _watch_deadlines() {
+    let (ref lock, ref cvar) = *_deadlines();
+    let mut deadlines = lock.lock().unwrap_or_else(PoisonError::into_inner);
+    loop {
+        let now = Instant::now();
+        deadlines = match deadlines.queue.peek() {
+            None => cvar.wait(deadlines).unwrap_or_else(PoisonError::into_inner),
+            Some(&Reverse((at, _))) if at > now => {
+                cvar.wait_timeout(deadlines, at - now).unwrap_or_else(PoisonError::into_inner).0
+            },
+            Some(&Reverse((_, id))) => {
+                deadlines.queue.pop();
+                match deadlines.tokens.remove(&id).and_then(|state| state.upgrade()) {
+                    Some(state) => {
+                        drop(deadlines);
+                        let token = CallToken{ deadline:None, state };
+                        token.abort( IPCError::new(ErrorCode::DeadlineExceeded, "deadline exceeded.") );
+                        lock.lock().unwrap_or_else(PoisonError::into_inner)
+                    },
+                    None => deadlines //answered or dropped meanwhile
+                }
+            }
+        };
+    }
+}
+
+/// Cancellation and deadline of one call, answering it exactly once.
+#[derive(Clone)]
+pub struct CallToken {
+    deadline: Option<Instant>,
+    state: Arc<Mutex<TokenState>>
+}
+
+impl CallToken {
+    /// Return a token expiring after `timeout`, if any.
+    pub fn new(timeout:Option<Duration>) -> Self {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        CallToken{ deadline, state:Arc::new(Default::default()) }
+    }
+
+    /// Cancel the call: answer it with `Cancelled`, and abandon the running job.
+    pub fn cancel(&self) {
+        self.abort( IPCError::new(ErrorCode::Cancelled, "cancelled by client.") );
+    }
+
+    pub fn is_answered(&self) -> bool {
+        self.state.lock().map(|s| s.answered).unwrap_or(true)
+    }
+
+    /// Bind the callback and the workers, and watch the deadline.
+    pub(crate) fn arm<CB>(&self, workers:&Workers, callback:CB)
+    where CB: FnOnce(IPCResult) + Send + 'static
+    {
+        if let Ok(mut state) = self.state.lock() {
+            state.answer = Some( Box::new(callback) );
+            state.workers = Some( workers.clone() );
+        }
+        if let Some(deadline) = self.deadline {
+            let (ref lock, ref cvar) = *_deadlines();
+            let mut deadlines = lock.lock().unwrap_or_else(PoisonError::into_inner);
+            let id = deadlines.next_id;
+            deadlines.next_id += 1;
+            deadlines.queue.push( Reverse((deadline, id)) );
+            deadlines.tokens.insert( id, Arc::downgrade(&self.state) );
+            if let Ok(mut state) = self.state.lock() {
+                state.deadline_id = Some(id);
+            }
+            cvar.notify_one();
+        }
+    }
+
+    /// Answer the call, unless already answered.
+    pub(crate) fn answer(&self, result:IPCResult) {
+        let (answer, deadline_id) = match self.state.lock() {
+            Ok(mut state) => {
+                state.answered = true;
+                ( state.answer.take(), state.deadline_id.take() )
+            },
+            Err(_) => (None, None)
+        };
+        _forget_deadline(deadline_id);
+        if let Some(answer) = answer {
+            answer(result);
+        }
+    }
+
+    fn abort(&self, err:IPCError) {
+        let (answer, interrupts, deadline_id) = match self.state.lock() {
+            Ok(mut state) => {
+                if state.answered {
+                    return;
+                }
+                state.answered = true;
+                state.aborted = true;
+                // replace the workers occupied by running jobs
+                if let Some(ref workers) = state.workers {
+                    if state.running > 0 {
+                        workers.grow(state.running);
+                    }
+                }
+                ( state.answer.take(), std::mem::take(&mut state.interrupts), state.deadline_id.take() )
+            },
+            Err(_) => return
+        };
+        _forget_deadline(deadline_id);
+        interrupts.iter().for_each(|f| f());
+        if let Some(answer) = answer {
+            answer( Err(err) );
+        }
+    }
+
+    /// Mark a job running; return `false` if the call is answered already.
+    pub(crate) fn begin(&self) -> bool {
+        match self.state.lock() {
+            Ok(mut state) if !state.answered => {
+                state.running += 1;
+                true
+            },
+            _ => false
+        }
+    }
+
+    /// Mark a job returned, and give back the worker replaced for it.
+    pub(crate) fn end(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.running -= 1;
+            if state.aborted {
+                if let Some(ref workers) = state.workers {
+                    workers.shrink();
+                }
+            }
+        }
+    }
+
+    /// Register a hook signalling the running job to stop, for cooperative cancellation.
+    pub(crate) fn on_interrupt(&self, f:Interrupt) {
+        if let Ok(mut state) = self.state.lock() {
+            state.interrupts.push(f);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn deadlines_share_one_watcher() {
+        let workers = Workers::new(1);
+        let (tx, rx) = mpsc::channel();
+        let tokens:Vec<CallToken> = [30, 10, 20].iter().map(|&ms| {
+            let token = CallToken::new( Some(Duration::from_millis(ms)) );
+            let tx = tx.clone();
+            token.arm(&workers, move |res| tx.send( (ms, res.map_err(|e| e.code)) ).unwrap_or(()));
+            token
+        }).collect();
+        let ids:Vec<u64> = tokens.iter().map(|t| t.state.lock().unwrap().deadline_id.unwrap()).collect();
+        let watched = |id:&u64| _deadlines().0.lock().unwrap().tokens.contains_key(id);
+        // answered before its deadline, never aborted
+        tokens[2].answer( Ok(Default::default()) );
+        assert!( !watched(&ids[2]) );
+
+        let mut answers:Vec<_> = rx.iter().take(3).collect();
+        answers.sort_by_key(|(ms, _)| *ms);
+        assert!( matches!(answers[0], (10, Err(ErrorCode::DeadlineExceeded))) );
+        assert!( matches!(answers[1], (20, Ok(_))) );
+        assert!( matches!(answers[2], (30, Err(ErrorCode::DeadlineExceeded))) );
+        assert!( !ids.iter().any(watched) );
+    }
+}
@@ -0,0 +1,246 @@
+use std::sync::{Arc, Mutex, PoisonError};
+use std::collections::VecDeque;
+//
+use crate::core::ffi::{FFIDescriptor, Arg};
+use crate::core::service::Service;
+use crate::core::error::{ErrorCode, IPCError, IPCResult};
+use crate::core::cancel::{CallToken, Workers};
+
+const PLACEHOLDER_PREFIX:&str = "restype_";
+
+type Step = (Result<Arc<Service>, IPCError>, String, Vec<Arg>);
+
+/// Skip the step depending on the failed step `idx`, keeping the step failed first.
+fn _upstream_failure(idx:usize, e:&IPCError) -> IPCError {
+    if e.code == ErrorCode::UpstreamFailed {
+        e.clone()
+    } else {
+        IPCError::new(ErrorCode::UpstreamFailed, format!("step {} failed: {}", idx, e))
+    }
+}
+
+/// The dependency graph of chained steps, by their `restype_<sig>_<func>` arguments.
+pub struct ChainPlan {
+    // (argument position, step) pairs filled in before each step
+    inputs: Vec<Vec<(usize, usize)>>,
+    // the steps depending on each step
+    dependents: Vec<Vec<usize>>
+}
+
+impl ChainPlan {
+    /// Resolve the placeholder arguments to the steps, failing on dangling references and cycles.
+    pub fn new(descriptors:&[FFIDescriptor]) -> Result<Self, IPCError> {
+        let sig_func_map:Vec<_> = descriptors.iter().map(|(sig, func, _)| {
+            format!("{}{}_{}", PLACEHOLDER_PREFIX, sig, func)
+        }).collect();
+
+        let mut inputs = Vec::with_capacity(descriptors.len());
+        let mut dependents = vec![Vec::new(); descriptors.len()];
+        for (i, (_, _, args)) in descriptors.iter().enumerate() {
+            let mut step_inputs = Vec::new();
+            for (pos, arg) in args.iter().enumerate() {
+                match arg {
+                    Arg::Str(arg) if arg.starts_with(PLACEHOLDER_PREFIX) => {
+                        let idx = sig_func_map.iter().position( |x|{x==arg} ).ok_or(
+                            IPCError::new(ErrorCode::MalformedRequest, format!("step {} refers to no step by '{}'.", i, arg))
+                        )?;
+                        step_inputs.push( (pos, idx) );
+                        if !dependents[idx].contains(&i) {
+                            dependents[idx].push(i);
+                        }
+                    },
+                    _ => {}
+                }
+            }
+            inputs.push(step_inputs);
+        }
+
+        let plan = ChainPlan{ inputs, dependents };
+        plan.order()?;
+        Ok(plan)
+    }
+
+    fn waiting(&self) -> Vec<usize> {
+        let mut waiting = vec![0; self.inputs.len()];
+        self.dependents.iter().flatten().for_each(|&i| waiting[i] += 1);
+        waiting
+    }
+
+    /// Return the steps in topological order, failing if any steps depend on each other in cycle.
+    pub fn order(&self) -> Result<Vec<usize>, IPCError> {
+        let mut waiting = self.waiting();
+        let mut ready:VecDeque<usize> = (0..waiting.len()).filter(|&i| waiting[i]==0).collect();
+        let mut order = Vec::with_capacity(waiting.len());
+        while let Some(i) = ready.pop_front() {
+            order.push(i);
+            for &j in &self.dependents[i] {
+                waiting[j] -= 1;
+                if waiting[j] == 0 {
+                    ready.push_back(j);
+                }
+            }
+        }
+        if order.len() < waiting.len() {
+            let cycle:Vec<_> = (0..waiting.len()).filter(|&i| waiting[i] > 0).collect();
+            return Err( IPCError::new(ErrorCode::MalformedRequest, format!("steps {:?} depend on each other in cycle.", cycle)) );
+        }
+        Ok(order)
+    }
+}
+
+struct ChainState {
+    steps: Vec<Option<Step>>,
+    // inputs not returned yet, of each step
+    waiting: Vec<usize>,
+    results: Vec<Option<IPCResult>>,
+    remaining: usize
+}
+
+/// One chain in flight: each step is dispatched to the pool once all of its inputs returned.
+#[derive(Clone)]
+pub struct Chain {
+    plan: Arc<ChainPlan>,
+    state: Arc<Mutex<ChainState>>,
+    pool: Workers,
+    token: CallToken
+}
+
+impl Chain {
+    pub fn new(plan:ChainPlan, steps:Vec<Step>, pool:&Workers, token:&CallToken) -> Self {
+        let state = ChainState{
+            waiting: plan.waiting(),
+            results: vec![None; steps.len()],
+            remaining: steps.len(),
+            steps: steps.into_iter().map(Some).collect()
+        };
+        Chain{
+            plan: Arc::new(plan), state: Arc::new(Mutex::new(state)),
+            pool: pool.clone(), token: token.clone()
+        }
+    }
+
+    /// Dispatch the steps without inputs, and answer the token once all the steps returned.
+    pub fn start(&self) {
+        let ready:Vec<usize> = match self.state.lock() {
+            Ok(state) => (0..state.waiting.len()).filter(|&i| state.waiting[i]==0).collect(),
+            Err(_) => return
+        };
+        if ready.is_empty() {
+            self.token.answer( Ok(Arg::default()) ); //empty chain
+        }
+        ready.into_iter().for_each(|i| self.dispatch(i));
+    }
+
+    /// Take the results of all the steps in order, once all returned.
+    pub fn results(&self) -> Vec<IPCResult> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.results.drain(..).flatten().collect()
+    }
+
+    fn dispatch(&self, i:usize) {
+        let chain = self.clone();
+        self.pool.execute(move || chain.run(i));
+    }
+
+    fn run(&self, i:usize) {
+        if self.token.is_answered() {
+            return; //cancelled or expired
+        }
+        // take the step with its inputs filled in, or the failure of any input
+        let (step, upstream) = {
+            let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+            let (service, func, mut args) = match state.steps[i].take() {
+                Some(step) => step,
+                None => return
+            };
+            let mut upstream = None;
+            for &(pos, idx) in &self.plan.inputs[i] {
+                match state.results[idx] {
+                    Some( Ok(ref res) ) => args[pos] = res.clone(),
+                    Some( Err(ref e) ) => { upstream = Some( _upstream_failure(idx, e) ); break },
+                    None => unreachable!("step {} dispatched before its input {}.", i, idx)
+                }
+            }
+            ( (service, func, args), upstream )
+        };
+
+        let result = match upstream {
+            Some(e) => Err(e),
+            None => {
+                if !self.token.begin() {
+                    return;
+                }
+                let (service, func, args) = step;
+                let result = service.and_then(|service| {
+                    service.call(&func, args, &self.token)
+                });
+                self.token.end();
+                result
+            }
+        };
+
+        // dispatch the dependents whose inputs all returned
+        let (ready, done) = {
+            let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+            state.results[i] = Some( result );
+            state.remaining -= 1;
+            let mut ready = Vec::new();
+            for &j in &self.plan.dependents[i] {
+                state.waiting[j] -= 1;
+                if state.waiting[j] == 0 {
+                    ready.push(j);
+                }
+            }
+            ( ready, state.remaining == 0 )
+        };
+        if done {
+            self.token.answer( Ok(Arg::default()) );
+        }
+        ready.into_iter().for_each(|j| self.dispatch(j));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step(sig:&str, func:&str, args:&[&str]) -> FFIDescriptor {
+        ( sig.into(), func.into(), args.iter().map(|&arg| Arg::Str(arg.into())).collect() )
+    }
+
+    #[test]
+    fn rejects_dangling_reference() {
+        let steps = vec![ step("a", "f", &[]), step("b", "g", &["1", "restype_a_g"]) ];
+        let err = ChainPlan::new(&steps).err().unwrap();
+        assert_eq!( err.code, ErrorCode::MalformedRequest );
+        assert!( err.message.contains("restype_a_g") );
+    }
+
+    #[test]
+    fn rejects_cycle() {
+        let steps = vec![
+            step("a", "f", &[]),
+            step("b", "g", &["restype_c_h"]),
+            step("c", "h", &["restype_a_f", "restype_b_g"])
+        ];
+        let err = ChainPlan::new(&steps).err().unwrap();
+        assert_eq!( err.code, ErrorCode::MalformedRequest );
+        assert!( err.message.contains("[1, 2]") );
+        // depending on itself
+        assert!( ChainPlan::new(&[step("a", "f", &["restype_a_f"])]).is_err() );
+    }
+
+    #[test]
+    fn orders_steps_after_their_inputs() {
+        let steps = vec![
+            step("a", "f", &["restype_c_h", "restype_b_g"]),
+            step("b", "g", &["restype_c_h"]),
+            step("c", "h", &["plain"]),
+            step("d", "k", &[])
+        ];
+        let plan = ChainPlan::new(&steps).unwrap();
+        assert_eq!( plan.order().unwrap(), vec![2, 3, 1, 0] );
+        assert_eq!( plan.inputs[0], vec![(0, 2), (1, 1)] );
+        assert_eq!( plan.waiting(), vec![2, 1, 0, 0] );
+    }
+}
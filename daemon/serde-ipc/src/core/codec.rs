@@ -0,0 +1,182 @@
+use serde_json::Value;
+//
+use crate::core::traits::Serde;
+use crate::core::ipc::{FEATURE_MSGPACK, FEATURE_CBOR, FEATURE_COMPRESSION, FEATURE_LZ4};
+
+pub struct Json;
+pub struct MsgPack;
+pub struct Cbor;
+
+impl Serde for Json {
+    type Value = Value;
+
+    fn to_raw_data(v:&Value) -> Result<Vec<u8>, String> {
+        serde_json::to_vec(v).map_err(|e| e.to_string())
+    }
+
+    fn from_raw_data(r:&[u8]) -> Result<Value, String> {
+        serde_json::from_slice(r).map_err(|e| e.to_string())
+    }
+}
+
+impl Serde for MsgPack {
+    type Value = Value;
+
+    fn to_raw_data(v:&Value) -> Result<Vec<u8>, String> {
+        rmp_serde::to_vec_named(v).map_err(|e| e.to_string())
+    }
+
+    fn from_raw_data(r:&[u8]) -> Result<Value, String> {
+        rmp_serde::from_slice(r).map_err(|e| e.to_string())
+    }
+}
+
+impl Serde for Cbor {
+    type Value = Value;
+
+    fn to_raw_data(v:&Value) -> Result<Vec<u8>, String> {
+        serde_cbor::to_vec(v).map_err(|e| e.to_string())
+    }
+
+    fn from_raw_data(r:&[u8]) -> Result<Value, String> {
+        serde_cbor::from_slice(r).map_err(|e| e.to_string())
+    }
+}
+
+/// The body format negotiated for one connection, JSON unless MessagePack or CBOR is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Codec {
+    Json,
+    MsgPack,
+    Cbor
+}
+
+impl Codec {
+    pub fn from_features(features:u32) -> Self {
+        if features & FEATURE_MSGPACK != 0 {
+            Codec::MsgPack
+        } else if features & FEATURE_CBOR != 0 {
+            Codec::Cbor
+        } else {
+            Codec::Json
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Codec::Json => "JSON",
+            Codec::MsgPack => "MessagePack",
+            Codec::Cbor => "CBOR"
+        }
+    }
+
+    pub fn encode(&self, v:&Value) -> Result<Vec<u8>, String> {
+        match self {
+            Codec::Json => Json::to_raw_data(v),
+            Codec::MsgPack => MsgPack::to_raw_data(v),
+            Codec::Cbor => Cbor::to_raw_data(v)
+        }
+    }
+
+    pub fn decode(&self, r:&[u8]) -> Result<Value, String> {
+        match self {
+            Codec::Json => Json::from_raw_data(r),
+            Codec::MsgPack => MsgPack::from_raw_data(r),
+            Codec::Cbor => Cbor::from_raw_data(r)
+        }
+    }
+}
+
+/// The compression negotiated for one connection, zstd preferred over lz4.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Zstd,
+    Lz4
+}
+
+impl Compression {
+    pub fn from_features(features:u32) -> Self {
+        if features & FEATURE_COMPRESSION != 0 {
+            Compression::Zstd
+        } else if features & FEATURE_LZ4 != 0 {
+            Compression::Lz4
+        } else {
+            Compression::None
+        }
+    }
+
+    pub fn compress(&self, data:&[u8]) -> Result<Vec<u8>, String> {
+        match self {
+            Compression::None => Ok( data.to_vec() ),
+            Compression::Zstd => zstd::bulk::compress(data, 0).map_err(|e| e.to_string()),
+            Compression::Lz4 => Ok( lz4_flex::compress_prepend_size(data) ) //[size:4B | block]
+        }
+    }
+
+    /// Decompress the data, failing if it would exceed `limit` bytes.
+    pub fn decompress(&self, data:&[u8], limit:usize) -> Result<Vec<u8>, String> {
+        match self {
+            Compression::None => Ok( data.to_vec() ),
+            Compression::Zstd => zstd::bulk::decompress(data, limit).map_err(|e| e.to_string()),
+            Compression::Lz4 => {
+                if data.len() < 4 {
+                    return Err( "lz4 block truncated.".into() );
+                }
+                let size = u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize;
+                if size > limit {
+                    return Err( format!("decompressed size exceeds {} bytes.", limit) );
+                }
+                lz4_flex::decompress_size_prepended(data).map_err(|e| e.to_string())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn codecs_round_trip() {
+        let v = json!({
+            "ok": {"name": "calc", "args": ["1", 2, -3, 4.5, true, null], "nested": {"empty": []}}
+        });
+        for codec in [Codec::Json, Codec::MsgPack, Codec::Cbor].iter() {
+            let raw = codec.encode(&v).unwrap();
+            assert_eq!( codec.decode(&raw).unwrap(), v, "{} round trip", codec.name() );
+        }
+        assert!( Codec::MsgPack.decode(b"\xc1").is_err() );
+    }
+
+    #[test]
+    fn codec_follows_features() {
+        assert_eq!( Codec::from_features(0), Codec::Json );
+        assert_eq!( Codec::from_features(FEATURE_CBOR), Codec::Cbor );
+        assert_eq!( Codec::from_features(FEATURE_MSGPACK|FEATURE_CBOR), Codec::MsgPack );
+    }
+
+    #[test]
+    fn compressions_round_trip() {
+        let data:Vec<u8> = b"capability ".iter().cycle().take(4096).cloned().collect();
+        for compression in [Compression::None, Compression::Zstd, Compression::Lz4].iter() {
+            let packed = compression.compress(&data).unwrap();
+            if *compression != Compression::None {
+                assert!( packed.len() < data.len() );
+            }
+            assert_eq!( compression.decompress(&packed, data.len()).unwrap(), data );
+        }
+        assert!( Compression::Lz4.decompress(b"\x01\x00", 16).is_err() );
+    }
+
+    #[test]
+    fn decompress_respects_limit() {
+        let data = vec![0u8; 4096];
+        for compression in [Compression::Zstd, Compression::Lz4].iter() {
+            let packed = compression.compress(&data).unwrap();
+            assert!( compression.decompress(&packed, data.len() - 1).is_err() );
+            assert!( compression.decompress(&packed, data.len()).is_ok() );
+        }
+    }
+}
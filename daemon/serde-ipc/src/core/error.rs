@@ -0,0 +1,55 @@
+use std::fmt;
+//
+use crate::core::ffi::Arg;
+
+/// Error codes carried in the `err` field of a response envelope.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum ErrorCode {
+    /// The command code in the request header is not recognized.
+    UnknownCommand      = 0x01,
+    /// The request body is not valid UTF-8 JSON, or misses required fields.
+    MalformedRequest    = 0x02,
+    /// No capability is installed (or loadable) under the given name.
+    UnknownCapability   = 0x03,
+    /// The usage signature is invalid or not registered.
+    BadSignature        = 0x04,
+    /// The function is not declared in metadata, or not found in the library.
+    UnknownFunction     = 0x05,
+    /// The number of arguments differs from the declared one.
+    ArgumentMismatch    = 0x06,
+    /// The function was called but failed to produce a result.
+    CalleeFailure       = 0x07,
+    /// The (reassembled) message exceeds the configured size limit.
+    PayloadTooLarge     = 0x08,
+    /// The call is cancelled by the client.
+    Cancelled           = 0x09,
+    /// The call did not return before its deadline.
+    DeadlineExceeded    = 0x0A,
+    /// The chained call is skipped, for a step it depends on failed.
+    UpstreamFailed      = 0x0B,
+    /// The capability registered from inside a service depends back on that service.
+    CapabilityCycle     = 0x0C,
+}
+
+#[derive(Debug, Clone)]
+pub struct IPCError {
+    pub code: ErrorCode,
+    pub message: String
+}
+
+pub type IPCResult = Result<Arg, IPCError>;
+
+impl IPCError {
+    pub fn new<S: Into<String>>(code:ErrorCode, message:S) -> Self {
+        IPCError{ code, message:message.into() }
+    }
+}
+
+impl fmt::Display for IPCError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for IPCError {}
@@ -0,0 +1,98 @@
+use std::sync::{Arc, Mutex, PoisonError};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// Deliver `(capability name, event, data)` to one subscriber; return `false` once it is gone.
+pub type EventSink = Box<dyn Fn(&str, &str, &str) -> bool + Send>;
+
+type SinkId = u64;
+
+#[derive(Default)]
+struct EventTable {
+    next_id: SinkId,
+    sinks: HashMap<SinkId, EventSink>,
+    subscriptions: BTreeMap<(String, String), BTreeSet<SinkId>>
+}
+
+/// Route the events emitted by capabilities to the subscribed connections.
+#[derive(Clone, Default)]
+pub struct EventBus {
+    table: Arc<Mutex<EventTable>>
+}
+
+impl EventBus {
+    /// Attach one subscriber, and return its id for (un)subscription.
+    pub fn attach(&self, sink:EventSink) -> SinkId {
+        let mut table = self.table.lock().unwrap_or_else(PoisonError::into_inner);
+        let id = table.next_id;
+        table.next_id += 1;
+        table.sinks.insert(id, sink);
+        id
+    }
+
+    /// Detach the subscriber with all its subscriptions.
+    pub fn detach(&self, id:SinkId) {
+        if let Ok(mut table) = self.table.lock() {
+            table.sinks.remove(&id);
+            table.subscriptions.values_mut().for_each(|ids| { ids.remove(&id); });
+            table.subscriptions.retain(|_, ids| !ids.is_empty());
+        }
+    }
+
+    pub fn subscribe(&self, id:SinkId, name:&str, event:&str) {
+        if let Ok(mut table) = self.table.lock() {
+            table.subscriptions.entry( (name.into(), event.into()) )
+                .or_insert_with(BTreeSet::new).insert(id);
+        }
+    }
+
+    /// Return `false` if the subscription does not exist.
+    pub fn unsubscribe(&self, id:SinkId, name:&str, event:&str) -> bool {
+        if let Ok(mut table) = self.table.lock() {
+            let key = (name.to_string(), event.to_string());
+            if let Some(ids) = table.subscriptions.get_mut(&key) {
+                let existed = ids.remove(&id);
+                if ids.is_empty() {
+                    table.subscriptions.remove(&key);
+                }
+                return existed;
+            }
+        }
+        false
+    }
+
+    /// Deliver the event to its subscribers, and detach those gone.
+    pub fn emit(&self, name:&str, event:&str, data:&str) {
+        let gone:Vec<SinkId> = match self.table.lock() {
+            Ok(table) => {
+                let key = (name.to_string(), event.to_string());
+                let ids = match table.subscriptions.get(&key) {
+                    Some(ids) => ids,
+                    None => return
+                };
+                ids.iter().filter(|id| {
+                    table.sinks.get(id).is_none_or(|sink| !sink(name, event, data))
+                }).cloned().collect()
+            },
+            Err(_) => return
+        };
+        gone.into_iter().for_each(|id| self.detach(id));
+    }
+
+    /// Return the emitter handed to the capability of `name`.
+    pub fn emitter(&self, name:&str) -> Emitter {
+        Emitter{ bus:self.clone(), name:name.into() }
+    }
+}
+
+/// Emit events on behalf of one capability.
+#[derive(Clone)]
+pub struct Emitter {
+    bus: EventBus,
+    name: String
+}
+
+impl Emitter {
+    pub fn emit(&self, event:&str, data:&str) {
+        self.bus.emit(&self.name, event, data);
+    }
+}
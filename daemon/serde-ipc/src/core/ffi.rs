@@ -1,19 +1,115 @@
 use std::path::PathBuf;
-use std::{thread, time};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, PoisonError, Weak};
 use std::collections::{HashMap, BTreeMap, BTreeSet};
 //
 use confy;
 use rand::{self, Rng};
 use serde::{Serialize,Deserialize};
-use threadpool::ThreadPool;
 //
 // use crate::core::traits::Serde;
 use crate::core::command::*;
 use crate::core::service::*;
+use crate::core::error::{ErrorCode, IPCError, IPCResult};
+use crate::core::event::EventBus;
+use crate::core::cancel::{CallToken, Workers};
+use crate::core::chain::{ChainPlan, Chain};
+use crate::core::host::Host;
 
 pub type ArcFFIManager = Arc<Mutex<FFIManager>>;
-pub type FFIDescriptor = (String, String, Vec<String>);
+pub type FFIDescriptor = (String, String, Vec<Arg>);
+
+/// The type name of raw bytes in metadata, as argument or result.
+pub const BYTES_TYPE:&str = "bytes";
+
+/// The primitive types of arguments and results declared in metadata.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgType {
+    Int,
+    Float,
+    Bool,
+    Str,
+    Bytes
+}
+
+impl ArgType {
+    /// Parse the type name, taking any other (e.g. `String`, `Array`) as string.
+    pub fn from_name(name:&str) -> Self {
+        match name {
+            "int" => ArgType::Int,
+            "float" => ArgType::Float,
+            "bool" => ArgType::Bool,
+            BYTES_TYPE => ArgType::Bytes,
+            _ => ArgType::Str
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ArgType::Int => "int",
+            ArgType::Float => "float",
+            ArgType::Bool => "bool",
+            ArgType::Str => "string",
+            ArgType::Bytes => BYTES_TYPE
+        }
+    }
+}
+
+/// Argument or result value of a function call.
+/// The results of `int`, `float` and `bool` functions stay typed, for the client to get JSON values.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Arg {
+    Str(String),
+    Bytes(Vec<u8>),
+    Int(i64),
+    Float(f64),
+    Bool(bool)
+}
+
+impl Default for Arg {
+    fn default() -> Self {
+        Arg::Str( String::new() )
+    }
+}
+
+impl From<String> for Arg {
+    fn from(s:String) -> Self {
+        Arg::Str(s)
+    }
+}
+
+impl From<Vec<u8>> for Arg {
+    fn from(b:Vec<u8>) -> Self {
+        Arg::Bytes(b)
+    }
+}
+
+impl Arg {
+    /// Turn typed values into their string form, as clients give them in arguments.
+    pub fn into_untyped(self) -> Arg {
+        match self {
+            Arg::Bytes(b) => Arg::Bytes(b),
+            arg => Arg::Str( arg.into_string().unwrap_or_default() )
+        }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        match self {
+            Arg::Bytes(b) => b,
+            arg => arg.into_string().unwrap_or_default().into_bytes()
+        }
+    }
+
+    /// Return the string form, or `None` for raw bytes.
+    pub fn into_string(self) -> Option<String> {
+        match self {
+            Arg::Str(s) => Some(s),
+            Arg::Bytes(_) => None,
+            Arg::Int(v) => Some( v.to_string() ),
+            Arg::Float(v) => Some( v.to_string() ),
+            Arg::Bool(v) => Some( v.to_string() )
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct BuildTemplate {
@@ -30,10 +126,24 @@ pub struct RuntimeTemplate {
     disable: Vec<String>
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct MetaFunc {
     pub restype: String,
-    pub args: Vec<(String, String)>
+    pub args: Vec<(String, String)>,
+    /// Whether the native string or bytes result is allocated per call, and released by `vdm_free`.
+    #[serde(default)]
+    pub owned: bool
+}
+
+impl MetaFunc {
+    /// The types of the `(name, type)` arguments, in order.
+    pub fn arg_types(&self) -> Vec<ArgType> {
+        self.args.iter().map(|(_, t)| ArgType::from_name(t)).collect()
+    }
+
+    pub fn res_type(&self) -> ArgType {
+        ArgType::from_name(&self.restype)
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -73,7 +183,10 @@ pub struct FFIManager {
     services: BTreeMap<ServiceSig, Arc<Service>>,
     service_map: ServiceMap,
     usage_map: UsageMap,
-    pool: ThreadPool
+    events: EventBus,
+    pool: Workers,
+    // handed to the services calling other capabilities
+    this: Weak<Mutex<FFIManager>>
 }
 
 // internal basic functions
@@ -82,9 +195,19 @@ impl FFIManager {
         let services = BTreeMap::new();
         let service_map = BTreeMap::new();
         let usage_map   = BTreeMap::new();
-        let pool = ThreadPool::new(num_cpus::get());
+        let events = EventBus::default();
+        let pool = Workers::new(num_cpus::get());
         std::env::set_current_dir(&root).unwrap(); //panic as you like
-        FFIManager{ root, services, service_map, usage_map, pool }
+        let this = Weak::new();
+        FFIManager{ root, services, service_map, usage_map, events, pool, this }
+    }
+
+    /// Share the manager, letting the services register and call other capabilities through it.
+    pub fn into_shared(mut self) -> ArcFFIManager {
+        Arc::new_cyclic(|this| {
+            self.this = this.clone();
+            Mutex::new(self)
+        })
     }
 
     fn write_config_file(&self, cfg: ServiceConfig) -> ExecResult {
@@ -141,7 +264,8 @@ impl FFIManager {
             }
         };
 
-        self.service_map.insert( name.into(), service_sig )?;
+        self.service_map.insert( name.into(), service_sig );
+        self.usage_map.insert( service_sig, BTreeSet::new() );
         Some( service_sig )
     }
 
@@ -158,11 +282,14 @@ impl FFIManager {
         Some( usage_sig )
     }
 
-    fn insert_service(&mut self, sig:ServiceSig, cfg: ServiceConfig) -> Option<()> {
-        let service = Service::load( &cfg.entry, cfg.metadata.unwrap() )?;
+    fn insert_service(&mut self, sig:ServiceSig, cfg: ServiceConfig) -> Result<(), String> {
+        let metadata = cfg.metadata.ok_or( "metadata missing.".to_string() )?;
+        let emitter = self.events.emitter(&metadata.name);
+        let host = Host::new(self.this.clone(), &metadata.name);
+        let service = Service::load( &cfg.entry, metadata, emitter, host )?;
         let service = Arc::new(service);
         self.services.insert(sig, service);
-        Some(())
+        Ok(())
     }
 
     fn cleanup(&mut self, srv_name:&String, srv_sig:&ServiceSig) {
@@ -170,14 +297,43 @@ impl FFIManager {
             if srv_usage.is_empty() {
                 self.usage_map.remove(srv_sig);
                 self.service_map.remove(srv_name);
-                self.services.remove(srv_sig);
+                // release the capabilities registered by the service in turn
+                if let Some(service) = self.services.remove(srv_sig) {
+                    for (name, sig) in service.host().release() {
+                        self.unregister(&name, &sig);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Whether the capability `from` is, or registers any capability depending on, the capability `to`.
+    pub(crate) fn depends_on(&self, from:&str, to:&str) -> bool {
+        let mut visited = BTreeSet::new();
+        let mut pending = vec![from.to_string()];
+        while let Some(name) = pending.pop() {
+            if name == to {
+                return true;
+            }
+            if !visited.insert(name.clone()) {
+                continue;
+            }
+            let service = self.service_map.get(&name).and_then(|sig| self.services.get(sig));
+            if let Some(service) = service {
+                pending.extend( service.host().dependencies() );
             }
         }
+        false
     }
 }
 
 // service install / uninstall
 impl FFIManager {
+    /// The bus where services emit events, and connections subscribe.
+    pub fn events(&self) -> EventBus {
+        self.events.clone()
+    }
+
     pub fn install(&self, directory:PathBuf, 
         metadata:Metadata, build:BuildTemplate, runtime:RuntimeTemplate) -> ExecResult 
     {
@@ -212,30 +368,40 @@ impl FFIManager {
 
 // service register / unregister
 impl FFIManager {
-    pub fn register(&mut self, name: &String) -> Option<String> {
+    pub fn register(&mut self, name: &String) -> Result<String, IPCError> {
+        let unknown_capability = |reason:&str| {
+            IPCError::new(ErrorCode::UnknownCapability, format!("'{}' {}.", name, reason))
+        };
         let service_sig = {
             if let Some(sig) = self.service_map.get(name) {
-                Some(*sig)
+                Ok(*sig)
             }
             else {
-                let cfg = self.load_config_file(name)?;
-                let srv_sig = self.insert_service_map(name)?; //"None" is always impossible
+                let cfg = self.load_config_file(name).ok_or( unknown_capability("not installed") )?;
+                let srv_sig = self.insert_service_map(name).ok_or( unknown_capability("not registered") )?; //"None" is always impossible
                 // try insert service; cleanup if failed.
-                if let Some(_) = self.insert_service(srv_sig, cfg) {
-                    Some(srv_sig)
-                }
-                else {
-                    self.cleanup(name, &srv_sig);
-                    None
+                match self.insert_service(srv_sig, cfg) {
+                    Ok(_) => Ok(srv_sig),
+                    Err(e) => {
+                        self.cleanup(name, &srv_sig);
+                        Err( unknown_capability(&format!("load failed: {}", e.trim_end_matches('.'))) )
+                    }
                 }
             }
         }?;
 
-        let usage_sig = self.insert_usage_map(&service_sig)?; //"None" is always impossible
+        let usage_sig = self.insert_usage_map(&service_sig).ok_or( unknown_capability("not registered") )?; //"None" is always impossible
         let srv_use_sig:u64 = ((service_sig as u64) << 32) + (usage_sig as u64);
-        Some( srv_use_sig.to_string() )
+        Ok( srv_use_sig.to_string() )
     }
     
+    /// Register the service like `register`, and return its function specification alongside.
+    pub fn register_with_spec(&mut self, name: &String) -> Result<(String, HashMap<String, MetaFunc>), IPCError> {
+        let srv_use_sig = self.register(name)?;
+        let spec = self.get_service_by_sig(&srv_use_sig)?.spec().clone();
+        Ok( (srv_use_sig, spec) )
+    }
+
     pub fn unregister(&mut self, name:&String, srv_use_sig: &String) {
         let srv_use_sig:u64 = srv_use_sig.parse().unwrap_or(0);
         let service_sig = (srv_use_sig >> 32) as u32;   //high u32
@@ -256,89 +422,121 @@ impl FFIManager {
 // service execute / chain_execute
 impl FFIManager
 {
-    fn get_service_by_sig(&self, srv_use_sig: &String) -> Option<Arc<Service>> {
-        let srv_use_sig:u64 = srv_use_sig.parse().unwrap_or(0);
+    pub(crate) fn get_service_by_sig(&self, srv_use_sig: &String) -> Result<Arc<Service>, IPCError> {
+        let bad_signature = || {
+            IPCError::new(ErrorCode::BadSignature, format!("'{}' is not a registered signature.", srv_use_sig))
+        };
+        let srv_use_sig:u64 = srv_use_sig.parse().or( Err(bad_signature()) )?;
         let service_sig = (srv_use_sig >> 32) as u32;   //high u32
         let usage_sig   = srv_use_sig as u32;           //low u32
 
-        let srv_usage = self.usage_map.get(&service_sig)?;
+        let srv_usage = self.usage_map.get(&service_sig).ok_or_else(bad_signature)?;
         if srv_usage.contains(&usage_sig) {
-            Some(Arc::clone(
-                self.services.get(&service_sig)?
+            Ok(Arc::clone(
+                self.services.get(&service_sig).ok_or_else(bad_signature)?
             ))
-        } else { None }
+        } else { Err(bad_signature()) }
     }
 
-    pub fn execute<CB>(&self, descriptor:FFIDescriptor, callback:CB)
-    where CB: FnOnce(String) -> () + Send + 'static,
+    /// Call the function, answering `callback` once with the result, or with the error
+    /// once `token` is cancelled or expired.
+    pub fn execute<CB>(&self, descriptor:FFIDescriptor, token:&CallToken, callback:CB)
+    where CB: FnOnce(IPCResult) + Send + 'static,
     {
         let (sig, func, args) = descriptor;
         let service = self.get_service_by_sig(&sig);
+        let token = token.clone();
+        token.arm(&self.pool, callback);
 
         self.pool.execute(move || {
-            let result = {
-                if let Some(service) = service {
-                    service.call(&func, args)
-                } else { None }
-            }.unwrap_or( String::new() );
-            callback(result);
+            if !token.begin() {
+                return; //cancelled before start
+            }
+            let result = service.and_then(|service| {
+                service.call(&func, args, &token)
+            });
+            token.end();
+            token.answer(result);
         });
     }
     
-    pub fn chain_execute<CB>(&self, descriptors:Vec<FFIDescriptor>, callback:CB)
-    where CB: FnOnce(String) -> () + Send + 'static
+    /// Call the independent functions in parallel like `execute`, answering once with all the results in order;
+    /// or with the error once `token` is cancelled or expired.
+    pub fn batch_execute<CB>(&self, descriptors:Vec<FFIDescriptor>, token:&CallToken, callback:CB)
+    where CB: FnOnce(Result<Vec<IPCResult>, IPCError>) + Send + 'static
     {
-        let shared_results:Vec<Option<String>> = vec![None; descriptors.len()];
+        let shared_results:Vec<Option<IPCResult>> = vec![None; descriptors.len()];
         let shared_results = Arc::new(Mutex::new( shared_results ));
-        let sig_func_map:Vec<_> = descriptors.iter().map(|(sig, func, _)| {
-            format!("restype_{}_{}", sig, func)
-        }).collect();
+        let remaining = Arc::new(Mutex::new( descriptors.len() ));
+        // the token is answered with an empty result once all the items returned
+        let _results = shared_results.clone();
+        token.arm(&self.pool, move |res| {
+            let results = res.map(|_| {
+                let mut _results = _results.lock().unwrap_or_else(PoisonError::into_inner);
+                _results.drain(..).flatten().collect() //all returned
+            });
+            callback(results);
+        });
+        if descriptors.is_empty() {
+            token.answer( Ok(Arg::default()) );
+            return;
+        }
 
-        for (i, (sig, func, mut args)) in descriptors.into_iter().enumerate() {
-            let shared_results = shared_results.clone();
+        for (i, (sig, func, args)) in descriptors.into_iter().enumerate() {
+            let (shared_results, remaining) = (shared_results.clone(), remaining.clone());
+            let token = token.clone();
             let service = self.get_service_by_sig(&sig);
-            let mut dep_map:Vec<_> = args.iter().enumerate().filter_map(|(pos, arg)| {
-                if arg.starts_with("restype") {
-                    let idx = sig_func_map.iter().position( |x|{x==arg} )?;
-                    Some( (pos, idx) )
-                } else { None }
-            }).collect();
-
-            self.pool.execute(move || loop {
-                let dep_map:Vec<_> = dep_map.drain(..).filter(|(pos,idx)|{
-                    if let Ok(_results) = shared_results.lock() {
-                        if let Some(ref res) = _results[*idx] {
-                            args[*pos] = res.clone();
-                            return false
-                        }
-                    }
-                    true
-                }).collect();
-
-                if dep_map.is_empty() {
-                    let result = {
-                        if let Some(ref service) = service {
-                            service.call(&func, args)
-                        } else { None }
-                    }.unwrap_or( String::new() );
-                    let mut _results = shared_results.lock().unwrap(); //panic as you like
-                    _results[i] = Some( result );
-                    break;
-                } else { thread::sleep(time::Duration::from_millis(10)); }
+
+            self.pool.execute(move || {
+                if !token.begin() {
+                    return; //cancelled before start
+                }
+                let result = service.and_then(|service| {
+                    service.call(&func, args, &token)
+                });
+                token.end();
+                shared_results.lock().unwrap_or_else(PoisonError::into_inner)[i] = Some( result );
+                let mut remaining = remaining.lock().unwrap_or_else(PoisonError::into_inner);
+                *remaining -= 1;
+                if *remaining == 0 {
+                    token.answer( Ok(Arg::default()) );
+                }
             });
         }
+    }
 
-        // create callback check thread
-        let shared_results = shared_results.clone();
-        self.pool.execute(move || loop {
-            if let Ok(_results) = shared_results.lock() {
-                if _results.iter().all( |x|{x.is_some()} ) {
-                    let res = _results.last().unwrap().clone().unwrap();
-                    callback(res);
-                    break
-                }
-            }
-            thread::sleep(time::Duration::from_millis(10));
+    /// Call the functions in chain like `execute`, answering with the result of the last one.
+    pub fn chain_execute<CB>(&self, descriptors:Vec<FFIDescriptor>, token:&CallToken, callback:CB)
+    where CB: FnOnce(IPCResult) + Send + 'static
+    {
+        self.chain_execute_all(descriptors, token, move |results| {
+            callback( results.and_then(|mut results| results.pop().unwrap_or( Ok(Arg::default()) )) );
         });
     }
+
+    /// Call the functions in chain like `execute`, answering with the results of all the steps in order.
+    /// The steps are dispatched once their inputs returned, and the chain is refused up front
+    /// if any `restype_<sig>_<func>` argument refers to no step, or the steps depend on each other in cycle.
+    /// A step depending on a failed one is skipped with `UpstreamFailed`, telling the failed step and why.
+    pub fn chain_execute_all<CB>(&self, descriptors:Vec<FFIDescriptor>, token:&CallToken, callback:CB)
+    where CB: FnOnce(Result<Vec<IPCResult>, IPCError>) + Send + 'static
+    {
+        let plan = match ChainPlan::new(&descriptors) {
+            Ok(plan) => plan,
+            Err(e) => {
+                token.arm(&self.pool, move |res| callback( res.map(|_| Vec::new()) ));
+                token.answer( Err(e) );
+                return;
+            }
+        };
+        let steps = descriptors.into_iter().map(|(sig, func, args)| {
+            ( self.get_service_by_sig(&sig), func, args )
+        }).collect();
+
+        // the token is answered with an empty result once all the steps returned
+        let chain = Chain::new(plan, steps, &self.pool, token);
+        let _chain = chain.clone();
+        token.arm(&self.pool, move |res| callback( res.map(|_| _chain.results()) ));
+        chain.start();
+    }
 }
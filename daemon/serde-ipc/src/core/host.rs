@@ -0,0 +1,360 @@
+use std::cell::RefCell;
+use std::sync::{Mutex, OnceLock, Weak};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::collections::{BTreeSet, HashMap};
+//
+use libc::{c_char, c_int, c_void};
+use std::ffi::{CStr, CString};
+use pyo3::prelude::*;
+use pyo3::exceptions::PyRuntimeError;
+use pyo3::types::{PyBytes, PyTuple};
+use pyo3::wrap_pyfunction;
+use vdm_capability_abi::VdmBytes;
+//
+use crate::core::ffi::{FFIManager, Arg};
+use crate::core::error::{ErrorCode, IPCError, IPCResult};
+use crate::core::cancel::CallToken;
+use crate::core::event::Emitter;
+
+thread_local! {
+    // the hosts of the services being called on this thread, innermost last
+    static FRAMES: RefCell<Vec<(*const Host, CallToken)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// The host of one service being called, popped once dropped.
+pub struct Frame;
+
+impl Frame {
+    pub fn enter(host:&Host, token:&CallToken) -> Self {
+        FRAMES.with(|frames| frames.borrow_mut().push( (host as *const Host, token.clone()) ));
+        Frame
+    }
+}
+
+impl Drop for Frame {
+    fn drop(&mut self) {
+        FRAMES.with(|frames| frames.borrow_mut().pop());
+    }
+}
+
+/// Return the token of the innermost call on this thread, or a fresh one outside any call.
+fn _current_token() -> CallToken {
+    FRAMES.with(|frames| frames.borrow().last().map(|(_, token)| token.clone()))
+        .unwrap_or_else(|| CallToken::new(None))
+}
+
+/// The capabilities one service registers and calls through the same `FFIManager`.
+/// The usages are released once the service is unloaded.
+pub struct Host {
+    ffi: Weak<Mutex<FFIManager>>,
+    name: String,
+    // (capability name, usage signature) pairs registered by the service
+    usages: Mutex<BTreeSet<(String, String)>>
+}
+
+impl Host {
+    pub fn new(ffi:Weak<Mutex<FFIManager>>, name:&str) -> Self {
+        Host{ ffi, name:name.into(), usages:Mutex::new(BTreeSet::new()) }
+    }
+
+    fn _unavailable() -> IPCError {
+        IPCError::new(ErrorCode::CalleeFailure, "host unavailable.")
+    }
+
+    /// The names of the capabilities registered by the service.
+    pub fn dependencies(&self) -> Vec<String> {
+        match self.usages.lock() {
+            Ok(usages) => usages.iter().map(|(name, _)| name.clone()).collect(),
+            Err(_) => Vec::new()
+        }
+    }
+
+    /// Take all the usages, to be unregistered along with the service.
+    pub fn release(&self) -> BTreeSet<(String, String)> {
+        match self.usages.lock() {
+            Ok(mut usages) => std::mem::take(&mut *usages),
+            Err(_) => BTreeSet::new()
+        }
+    }
+
+    /// Register the capability like a client does, refusing any capability depending back on the service.
+    pub fn register(&self, name:&String) -> Result<String, IPCError> {
+        let ffi = self.ffi.upgrade().ok_or_else(Self::_unavailable)?;
+        let mut ffi = ffi.lock().or( Err(Self::_unavailable()) )?;
+        if ffi.depends_on(name, &self.name) {
+            return Err( IPCError::new(ErrorCode::CapabilityCycle,
+                format!("'{}' depends on '{}' already.", name, self.name)) );
+        }
+        let sig = ffi.register(name)?;
+        if let Ok(mut usages) = self.usages.lock() {
+            usages.insert( (name.clone(), sig.clone()) );
+        }
+        Ok(sig)
+    }
+
+    /// Return `false` if the signature is not registered by the service.
+    pub fn unregister(&self, sig:&str) -> bool {
+        let usage = match self.usages.lock() {
+            Ok(mut usages) => {
+                let usage = usages.iter().find(|(_, s)| s==sig).cloned();
+                usage.filter(|usage| usages.remove(usage))
+            },
+            Err(_) => None
+        };
+        match (usage, self.ffi.upgrade()) {
+            (Some((name, sig)), Some(ffi)) => {
+                if let Ok(mut ffi) = ffi.lock() {
+                    ffi.unregister(&name, &sig);
+                }
+                true
+            },
+            (usage, _) => usage.is_some()
+        }
+    }
+
+    /// Call the function of a capability registered by the service, inline on the current thread
+    /// and under the token of the ongoing call.
+    pub fn execute(&self, sig:&str, func:&String, args:Vec<Arg>) -> IPCResult {
+        let registered = self.usages.lock().map(|usages| usages.iter().any(|(_, s)| s==sig)).unwrap_or(false);
+        if !registered {
+            return Err( IPCError::new(ErrorCode::BadSignature,
+                format!("'{}' is not registered by '{}'.", sig, self.name)) );
+        }
+        let service = {
+            let ffi = self.ffi.upgrade().ok_or_else(Self::_unavailable)?;
+            let ffi = ffi.lock().or( Err(Self::_unavailable()) )?;
+            ffi.get_service_by_sig(&sig.to_string())?
+        };
+        service.call(func, args, &_current_token())
+    }
+}
+
+//================================================================================//
+
+/// The host callbacks handed to native capabilities exporting `vdm_set_host`.
+/// The returned strings are owned by the caller, and released with `free_str`;
+/// on failure, `NULL` is returned and `*err` (if not `NULL`) is set to the error message.
+/// Bytes results are returned by `execute_bytes` only, into `*res` released with `free_bytes`.
+#[repr(C)]
+pub struct VdmHost {
+    pub ctx: *mut c_void,
+    pub register_capability: extern "C" fn(*mut c_void, *const c_char, *mut *mut c_char) -> *mut c_char,
+    pub unregister_capability: extern "C" fn(*mut c_void, *const c_char) -> c_int,
+    pub execute: extern "C" fn(*mut c_void, *const c_char, *const c_char, *const *const c_char, usize, *mut *mut c_char) -> *mut c_char,
+    pub free_str: extern "C" fn(*mut c_char),
+    pub execute_bytes: extern "C" fn(*mut c_void, *const c_char, *const c_char, *const *const c_char, usize, *mut VdmBytes, *mut *mut c_char) -> c_int,
+    pub free_bytes: extern "C" fn(VdmBytes)
+}
+
+// immutable once built, pointing to the host shared by the calling threads
+unsafe impl Send for VdmHost {}
+unsafe impl Sync for VdmHost {}
+
+impl VdmHost {
+    pub fn new(host:&Host) -> Self {
+        VdmHost{
+            ctx: host as *const Host as *mut c_void,
+            register_capability: register_trampoline,
+            unregister_capability: unregister_trampoline,
+            execute: execute_trampoline,
+            free_str: free_str_trampoline,
+            execute_bytes: execute_bytes_trampoline,
+            free_bytes: free_bytes_trampoline
+        }
+    }
+}
+
+fn _set_err(e:IPCError, err:*mut *mut c_char) {
+    if !err.is_null() {
+        let msg = CString::new( e.to_string().replace('\0', "") ).unwrap_or_default();
+        unsafe{ *err = msg.into_raw() };
+    }
+}
+
+fn _into_c_str(result:Result<String, IPCError>, err:*mut *mut c_char) -> *mut c_char {
+    let result = result.and_then(|s| {
+        CString::new(s).or( Err(IPCError::new(ErrorCode::CalleeFailure, "result contains nul byte.")) )
+    });
+    match result {
+        Ok(s) => s.into_raw(),
+        Err(e) => {
+            _set_err(e, err);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+fn _from_c_str(s:*const c_char) -> Result<String, IPCError> {
+    if s.is_null() {
+        return Err( IPCError::new(ErrorCode::MalformedRequest, "null string given to host.") );
+    }
+    Ok( unsafe{ CStr::from_ptr(s) }.to_string_lossy().into_owned() )
+}
+
+extern "C" fn register_trampoline(ctx:*mut c_void, name:*const c_char, err:*mut *mut c_char) -> *mut c_char {
+    let host = unsafe{ &*(ctx as *const Host) };
+    _into_c_str( _from_c_str(name).and_then(|name| host.register(&name)), err )
+}
+
+extern "C" fn unregister_trampoline(ctx:*mut c_void, sig:*const c_char) -> c_int {
+    let host = unsafe{ &*(ctx as *const Host) };
+    match _from_c_str(sig) {
+        Ok(sig) if host.unregister(&sig) => 0,
+        _ => -1
+    }
+}
+
+fn _execute(ctx:*mut c_void, sig:*const c_char, func:*const c_char,
+    args:*const *const c_char, argc:usize) -> Result<(String, Arg), IPCError>
+{
+    let host = unsafe{ &*(ctx as *const Host) };
+    let (sig, func) = ( _from_c_str(sig)?, _from_c_str(func)? );
+    let args = if argc==0 { Vec::new() } else {
+        if args.is_null() {
+            return Err( IPCError::new(ErrorCode::MalformedRequest, "null arguments given to host.") );
+        }
+        unsafe{ std::slice::from_raw_parts(args, argc) }.iter()
+            .map(|&arg| _from_c_str(arg).map(Arg::Str)).collect::<Result<_,_>>()?
+    };
+    host.execute(&sig, &func, args).map(|res| (func, res))
+}
+
+extern "C" fn execute_trampoline(ctx:*mut c_void, sig:*const c_char, func:*const c_char,
+    args:*const *const c_char, argc:usize, err:*mut *mut c_char) -> *mut c_char
+{
+    let result = _execute(ctx, sig, func, args, argc).and_then(|(func, res)| match res {
+        Arg::Bytes(_) => Err( IPCError::new(ErrorCode::CalleeFailure,
+            format!("'{}' returns bytes, given by 'execute_bytes' only.", func)) ),
+        res => Ok( res.into_string().unwrap_or_default() )
+    });
+    _into_c_str(result, err)
+}
+
+extern "C" fn free_str_trampoline(s:*mut c_char) {
+    if !s.is_null() {
+        unsafe{ drop(CString::from_raw(s)) };
+    }
+}
+
+extern "C" fn execute_bytes_trampoline(ctx:*mut c_void, sig:*const c_char, func:*const c_char,
+    args:*const *const c_char, argc:usize, res:*mut VdmBytes, err:*mut *mut c_char) -> c_int
+{
+    if res.is_null() {
+        _set_err( IPCError::new(ErrorCode::MalformedRequest, "null result given to host."), err );
+        return -1;
+    }
+    match _execute(ctx, sig, func, args, argc) {
+        Ok((_, data)) => {
+            // allocated here, and released by `free_bytes`
+            let data = data.into_bytes().into_boxed_slice();
+            let len = data.len();
+            unsafe{ *res = VdmBytes{ ptr:Box::into_raw(data) as *const u8, len } };
+            0
+        },
+        Err(e) => {
+            _set_err(e, err);
+            -1
+        }
+    }
+}
+
+extern "C" fn free_bytes_trampoline(data:VdmBytes) {
+    if !data.ptr.is_null() {
+        let data = std::ptr::slice_from_raw_parts_mut(data.ptr as *mut u8, data.len);
+        unsafe{ drop(Box::from_raw(data)) };
+    }
+}
+
+//================================================================================//
+
+/// The emitters of the loaded python capabilities, by the id injected into their module.
+fn _py_emitters() -> &'static Mutex<HashMap<u64, Emitter>> {
+    static EMITTERS:OnceLock<Mutex<HashMap<u64, Emitter>>> = OnceLock::new();
+    EMITTERS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// The emitter of one python capability reachable from any thread, until dropped with the service.
+pub struct PyEmitter(u64);
+
+impl PyEmitter {
+    pub fn register(emitter:Emitter) -> Self {
+        static NEXT_ID:AtomicU64 = AtomicU64::new(0);
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        if let Ok(mut emitters) = _py_emitters().lock() {
+            emitters.insert(id, emitter);
+        }
+        PyEmitter(id)
+    }
+
+    pub fn id(&self) -> u64 {
+        self.0
+    }
+}
+
+impl Drop for PyEmitter {
+    fn drop(&mut self) {
+        if let Ok(mut emitters) = _py_emitters().lock() {
+            emitters.remove(&self.0);
+        }
+    }
+}
+
+/// Run `f` with the host of the innermost python call on this thread,
+/// without the GIL held, for the manager may be locked by a thread loading another python capability.
+fn _with_host<T>(f:impl FnOnce(&Host) -> Result<T, IPCError>) -> PyResult<T> {
+    let host = FRAMES.with(|frames| frames.borrow().last().map(|(host, _)| *host));
+    let host = host.ok_or_else(|| PyRuntimeError::new_err("vdm is available only during calls."))?;
+    f( unsafe{ &*host } ).map_err(|e| PyRuntimeError::new_err( e.to_string() ))
+}
+
+/// `vdm.register(name)`: register the capability, returning its usage signature.
+#[pyfunction]
+fn register(py:Python, name:String) -> PyResult<String> {
+    py.allow_threads(|| _with_host(|host| host.register(&name)))
+}
+
+/// `vdm.unregister(sig)`: return `False` if the signature is not registered by the capability.
+#[pyfunction]
+fn unregister(py:Python, sig:String) -> PyResult<bool> {
+    py.allow_threads(|| _with_host(|host| Ok( host.unregister(&sig) )))
+}
+
+/// `vdm.execute(sig, func, *args)`: call the function of a registered capability.
+#[pyfunction(args="*")]
+fn execute(py:Python, sig:String, func:String, args:&PyTuple) -> PyResult<PyObject> {
+    let args:Vec<Arg> = args.iter().map(|arg| {
+        match arg.downcast::<PyBytes>() {
+            Ok(b) => Ok( Arg::Bytes(b.as_bytes().to_vec()) ),
+            Err(_) => arg.str().map(|s| Arg::Str(s.to_string()))
+        }
+    }).collect::<PyResult<_>>()?;
+    let res = py.allow_threads(|| _with_host(|host| host.execute(&sig, &func, args)))?;
+    Ok( match res {
+        Arg::Str(s) => s.into_py(py),
+        Arg::Bytes(b) => PyBytes::new(py, &b).into_py(py),
+        Arg::Int(v) => v.into_py(py),
+        Arg::Float(v) => v.into_py(py),
+        Arg::Bool(v) => v.into_py(py)
+    } )
+}
+
+/// `vdm.emit(id, event, data)`: emit the event at once, dropped if the capability is unloaded.
+#[pyfunction]
+fn emit(py:Python, id:u64, event:String, data:String) {
+    py.allow_threads(|| {
+        let emitter = _py_emitters().lock().ok().and_then(|emitters| emitters.get(&id).cloned());
+        if let Some(emitter) = emitter {
+            emitter.emit(&event, &data);
+        }
+    })
+}
+
+/// Build the `vdm` module injected into python capabilities.
+pub fn py_module<'py>(py:Python<'py>) -> PyResult<&'py PyModule> {
+    let module = PyModule::new(py, "vdm")?;
+    module.add_function( wrap_pyfunction!(register, module)? )?;
+    module.add_function( wrap_pyfunction!(unregister, module)? )?;
+    module.add_function( wrap_pyfunction!(execute, module)? )?;
+    module.add_function( wrap_pyfunction!(emit, module)? )?;
+    Ok(module)
+}
@@ -1,11 +1,17 @@
 extern crate libc;
 
 // standard library
+use std::{fs, io};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::net::{SocketAddr,};
+use std::os::unix::fs::PermissionsExt;
 // third-party crates
-use tokio::net::{TcpStream, TcpListener};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, TcpListener, UnixStream, UnixListener};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncReadExt, AsyncWriteExt};
+use tokio::sync::watch;
 
 // root crates
 use crate::core::ffi::ArcFFIManager;
@@ -13,93 +19,446 @@ use crate::core::traits::IPCProtocol;
 
 const VDM_CLIENT_ID_LEN:usize = 16;
 
+/// The wire protocol version spoken by this daemon.
+pub const PROTOCOL_VERSION:u16 = 1;
+/// The oldest wire protocol version still accepted.
+pub const MIN_PROTOCOL_VERSION:u16 = 1;
+
+// feature bitmap exchanged during handshake
+pub const FEATURE_CHUNKING:u32      = 0x0001;
+pub const FEATURE_COMPRESSION:u32   = 0x0002; //zstd
+pub const FEATURE_BINARY:u32        = 0x0004;
+pub const FEATURE_PUSH_EVENTS:u32   = 0x0008;
+pub const FEATURE_SOCKET_TRANSPORT:u32  = 0x0010;
+pub const FEATURE_MSGPACK:u32       = 0x0020;
+pub const FEATURE_CBOR:u32          = 0x0040;
+pub const FEATURE_LZ4:u32           = 0x0080;
+
+// handshake status code
+const HS_ACCEPTED:u16               = 0x00;
+const HS_VERSION_UNSUPPORTED:u16    = 0x01;
+
+/// handshake-I: `[id:16B | version:2B | features:4B]`
+const HELLO_LEN:usize = VDM_CLIENT_ID_LEN + 2 + 4;
+
+/// The address where the handshake listener binds.
+#[derive(Debug, Clone)]
+pub enum ServerAddr {
+    /// Unix domain socket at the path, only accepting peers of the same user.
+    Unix(PathBuf),
+    /// TCP socket on `127.0.0.1` with the port, accepting any local user.
+    Tcp(u16)
+}
+
+impl Default for ServerAddr {
+    fn default() -> Self {
+        ServerAddr::Unix( default_socket_path() )
+    }
+}
+
+/// Return `$XDG_RUNTIME_DIR/vdm/capability.sock`, or `/tmp/vdm-<uid>/capability.sock` if unset.
+pub fn default_socket_path() -> PathBuf {
+    let runtime_dir = match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join("vdm"),
+        None => PathBuf::from( format!("/tmp/vdm-{}", unsafe{ libc::geteuid() }) )
+    };
+    runtime_dir.join("capability.sock")
+}
+
+/// Prepare a private directory for the socket, and remove the stale socket file.
+fn prepare_socket_path(path:&Path) -> Result<(), String> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .or( Err(format!("create directory '{}' failed.", parent.display())) )?;
+        fs::set_permissions(parent, fs::Permissions::from_mode(0o700))
+            .or( Err(format!("set permission of '{}' failed.", parent.display())) )?;
+    }
+    if path.exists() {
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err( format!("'{}' is in use by another daemon.", path.display()) );
+        }
+        fs::remove_file(path)
+            .or( Err(format!("remove stale socket '{}' failed.", path.display())) )?;
+    }
+    Ok(())
+}
+
+/// Transport limits handed to every `IPCProtocol` connection.
+#[derive(Debug, Clone)]
+pub struct IPCConfig {
+    /// Size of the response ring buffer segment created by daemon, in bytes.
+    pub res_size: usize,
+    /// Upper bound of one reassembled (chunked) message, and of all the partial ones of a connection, in bytes.
+    pub max_message_size: usize,
+    /// Protocol version; the negotiated one when handed to a connection.
+    pub version: u16,
+    /// Feature bitmap; the negotiated one when handed to a connection.
+    pub features: u32,
+    /// Period to reap the exited connections.
+    pub reap_interval: Duration,
+    /// Time to wait for connections to exit on shutdown.
+    pub shutdown_timeout: Duration,
+    /// Bodies larger than this are compressed, if compression is negotiated.
+    pub compress_threshold: usize,
+    /// Time without any frame before a peer of unknown process is considered dead.
+    pub peer_timeout: Duration,
+    /// Process id of the peer learned at handshake, if the transport tells.
+    pub peer_pid: Option<i32>
+}
+
+impl Default for IPCConfig {
+    fn default() -> Self {
+        IPCConfig {
+            res_size: 1024*1024,                //1MB
+            max_message_size: 64*1024*1024,     //64MB
+            version: PROTOCOL_VERSION,
+            features: FEATURE_CHUNKING | FEATURE_BINARY | FEATURE_PUSH_EVENTS | FEATURE_SOCKET_TRANSPORT
+                    | FEATURE_MSGPACK | FEATURE_CBOR | FEATURE_COMPRESSION | FEATURE_LZ4,
+            reap_interval: Duration::from_secs(5),
+            shutdown_timeout: Duration::from_secs(3),
+            compress_threshold: 16*1024,        //16KB
+            peer_timeout: Duration::from_secs(30),
+            peer_pid: None
+        }
+    }
+}
+
+/// The blocking handshake socket, handed over to the protocol framing over it.
+pub enum IPCStream {
+    Unix(std::os::unix::net::UnixStream),
+    Tcp(std::net::TcpStream)
+}
+
+impl IPCStream {
+    pub fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Self::Unix(s) => Ok( Self::Unix(s.try_clone()?) ),
+            Self::Tcp(s) => Ok( Self::Tcp(s.try_clone()?) )
+        }
+    }
+
+    pub fn shutdown(&self) {
+        match self {
+            Self::Unix(s) => s.shutdown(std::net::Shutdown::Both).unwrap_or(()),
+            Self::Tcp(s) => s.shutdown(std::net::Shutdown::Both).unwrap_or(())
+        }
+    }
+}
+
+impl Read for IPCStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Unix(s) => s.read(buf),
+            Self::Tcp(s) => s.read(buf)
+        }
+    }
+}
+
+impl Write for IPCStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Unix(s) => s.write(buf),
+            Self::Tcp(s) => s.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Unix(s) => s.flush(),
+            Self::Tcp(s) => s.flush()
+        }
+    }
+}
+
+pub trait IntoIPCStream {
+    fn into_ipc_stream(self) -> io::Result<IPCStream>;
+    /// Process id of the peer, if available from the socket.
+    fn peer_pid(&self) -> Option<i32>;
+}
+
+impl IntoIPCStream for TcpStream {
+    fn into_ipc_stream(self) -> io::Result<IPCStream> {
+        let stream = self.into_std()?;
+        stream.set_nonblocking(false)?;
+        Ok( IPCStream::Tcp(stream) )
+    }
+
+    fn peer_pid(&self) -> Option<i32> {
+        None
+    }
+}
+
+impl IntoIPCStream for UnixStream {
+    fn into_ipc_stream(self) -> io::Result<IPCStream> {
+        let stream = self.into_std()?;
+        stream.set_nonblocking(false)?;
+        Ok( IPCStream::Unix(stream) )
+    }
+
+    fn peer_pid(&self) -> Option<i32> {
+        self.peer_cred().ok()?.pid()
+    }
+}
+
+struct Hello {
+    id: String,
+    version: u16,
+    features: u32
+}
+
+impl Hello {
+    fn from_bytes(buf:&[u8]) -> Option<Self> {
+        match buf.len() {
+            // legacy client without version
+            VDM_CLIENT_ID_LEN => Some(Hello{
+                id: String::from_utf8(buf.to_vec()).ok()?, version:0, features:0
+            }),
+            HELLO_LEN => {
+                let (id, rest) = buf.split_at(VDM_CLIENT_ID_LEN);
+                let mut version = [0u8; 2];
+                let mut features = [0u8; 4];
+                version.copy_from_slice(&rest[..2]);
+                features.copy_from_slice(&rest[2..]);
+                Some(Hello{
+                    id: String::from_utf8(id.to_vec()).ok()?,
+                    version: u16::from_le_bytes(version),
+                    features: u32::from_le_bytes(features)
+                })
+            },
+            _ => None
+        }
+    }
+}
+
+/// handshake-II: `[id:16B | version:2B | features:4B | status:2B | msg_len:2B | msg]`
+fn welcome_bytes(id:&str, version:u16, features:u32, status:u16, msg:&str) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(HELLO_LEN + 4 + msg.len());
+    buf.extend( id.as_bytes() );
+    buf.extend( &version.to_le_bytes() );
+    buf.extend( &features.to_le_bytes() );
+    buf.extend( &status.to_le_bytes() );
+    buf.extend( &(msg.len() as u16).to_le_bytes() );
+    buf.extend( msg.as_bytes() );
+    buf
+}
+
 pub struct IPCServer<P>
 where P:IPCProtocol
 {
-    server_port:u16,
+    server_addr: ServerAddr,
+    config: IPCConfig,
     ffi: ArcFFIManager,
-    conns: Vec<P>
+    conns: Vec<P>,
+    stop_tx: watch::Sender<bool>,
+    stop_rx: watch::Receiver<bool>
 }
 
 impl<P> IPCServer<P>
 where P:IPCProtocol
 {
-    pub fn new(server_port:u16, ffi: ArcFFIManager) -> Arc<Mutex<Self>> {
+    pub fn new(server_addr:ServerAddr, config:IPCConfig, ffi: ArcFFIManager) -> Arc<Mutex<Self>> {
+        let (stop_tx, stop_rx) = watch::channel(false);
         Arc::new(Mutex::new(
             IPCServer{
-                server_port, ffi, conns:Vec::new()
+                server_addr, config, ffi, conns:Vec::new(), stop_tx, stop_rx
             }
         ))
     }
 
-    async fn try_connect(_self: Arc<Mutex<Self>>, mut socket:TcpStream) {
-        let mut buf = [0; VDM_CLIENT_ID_LEN+1];
-        let mut id_buf = Vec::<u8>::new();
+    /// Stop and drop the connections whose threads have exited.
+    fn reap(_self:&Arc<Mutex<Self>>) {
+        if let Ok(mut _self) = _self.lock() {
+            _self.conns.retain(|x| {
+                if x.is_alive() { true } else { x.stop(); false }
+            });
+        }
+    }
+
+    /// Stop the accept loop and all connections, then wait for them to exit.
+    /// Return `false` if some connection is still running after `timeout`.
+    pub fn shutdown(_self:Arc<Mutex<Self>>, timeout:Duration) -> bool {
+        let (conns, server_addr) = match _self.lock() {
+            Ok(mut _self) => {
+                _self.stop_tx.send(true).unwrap_or(());
+                ( _self.conns.drain(..).collect::<Vec<_>>(), _self.server_addr.clone() )
+            },
+            Err(_) => return false
+        };
+        if let ServerAddr::Unix(path) = server_addr {
+            fs::remove_file(path).unwrap_or(());
+        }
+
+        conns.iter().for_each(|x| x.stop());
+        let deadline = Instant::now() + timeout;
+        conns.iter().all(|x| {
+            x.join( deadline.saturating_duration_since(Instant::now()) )
+        })
+    }
+
+    async fn try_connect<S>(_self: Arc<Mutex<Self>>, mut socket:S)
+    where S: AsyncRead + AsyncWrite + IntoIPCStream + Unpin
+    {
+        let mut buf = [0; HELLO_LEN];
         let (tx, rx) = mpsc::channel::< P::Message >();
 
-        // handshake-I(a): recv id
+        // handshake-I(a): recv id, version and features
         let n = match socket.read(&mut buf).await {
             Ok(n) => n,
             Err(e) => {
-                eprintln!("hs1: failed to write to socket; err = {:?}", e);
+                eprintln!("hs1: failed to read from socket; err = {:?}", e);
                 return
             }
         };
-        id_buf.extend( buf[..n].iter().copied() );
-        let _id = std::str::from_utf8(&id_buf).unwrap();
-        let mut _protocol = {
-            if let Ok(mut _self) = _self.lock() {
-                let ffi = _self.ffi.clone();
-                Some( P::new(format!("{}", _id), ffi) )
+        let hello = match Hello::from_bytes(&buf[..n]) {
+            Some(hello) => hello,
+            None => {
+                eprintln!("hs1: malformed hello of {} bytes.", n);
+                return
+            }
+        };
+
+        // handshake-I(b): negotiate version and features
+        let mut config = {
+            if let Ok(_self) = _self.lock() {
+                Some( _self.config.clone() )
             } else {None}
         }.unwrap();
+        if hello.version < MIN_PROTOCOL_VERSION {
+            let msg = format!("protocol version {} unsupported, requires {} to {}.",
+                        hello.version, MIN_PROTOCOL_VERSION, config.version);
+            let welcome = welcome_bytes(&hello.id, config.version, 0, HS_VERSION_UNSUPPORTED, &msg);
+            if let Err(e) = socket.write_all(&welcome).await {
+                eprintln!("hs2: failed to write to socket; err = {:?}", e);
+            }
+            return;
+        }
+        config.version = config.version.min(hello.version);
+        config.features &= hello.features;
+        if config.features & FEATURE_MSGPACK != 0 {
+            config.features &= !FEATURE_CBOR; //one body format, MessagePack preferred
+        }
+        if config.features & FEATURE_COMPRESSION != 0 {
+            config.features &= !FEATURE_LZ4; //one compression, zstd preferred
+        }
+        config.peer_pid = socket.peer_pid();
 
-        // handshake-I(b): spawn "send" thread
-        _protocol.spawn_send_thread(rx);
+        // handshake-I(c): spawn "send" thread, unless framing over this socket
+        let (version, features) = (config.version, config.features);
+        let ffi = {
+            if let Ok(_self) = _self.lock() {
+                Some( _self.ffi.clone() )
+            } else {None}
+        }.unwrap();
+        let mut rx = Some(rx);
+        let mut _protocol = None;
+        if features & FEATURE_SOCKET_TRANSPORT == 0 {
+            let mut protocol = P::new(hello.id.clone(), config.clone(), ffi.clone(), None);
+            protocol.spawn_send_thread( rx.take().unwrap() );
+            _protocol = Some(protocol);
+        }
 
         // handshake-II: write back
-        if let Err(e) = socket.write_all(&buf).await {
+        let welcome = welcome_bytes(&hello.id, version, features, HS_ACCEPTED, "");
+        if let Err(e) = socket.write_all(&welcome).await {
             eprintln!("hs2: failed to write to socket; err = {:?}", e);
             return;
         }
 
         // handshake-III: spawn "recv" thread
-        if let Err(e) = socket.read(&mut buf).await {
+        if let Err(e) = socket.read_exact(&mut buf[..VDM_CLIENT_ID_LEN]).await {
             eprintln!("hs3: failed to read from socket; err = {:?}", e);
             return;
         }
+        let mut _protocol = match _protocol {
+            Some(protocol) => protocol,
+            None => {
+                let stream = match socket.into_ipc_stream() {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        eprintln!("hs3: failed to hand over socket; err = {:?}", e);
+                        return;
+                    }
+                };
+                let mut protocol = P::new(hello.id.clone(), config, ffi, Some(stream));
+                protocol.spawn_send_thread( rx.take().unwrap() );
+                protocol
+            }
+        };
         _protocol.spawn_recv_thread(tx);
 
-        // record this connection
+        // record this connection, unless shutdown meanwhile
         if let Ok(mut _self) = _self.lock() {
-            _self.conns.push( _protocol );
+            if *_self.stop_rx.borrow() {
+                _protocol.stop();
+            } else {
+                _self.conns.push( _protocol );
+            }
         }
     }
 
+    fn spawn_connect<S>(_self:Arc<Mutex<Self>>, socket:S)
+    where S: AsyncRead + AsyncWrite + IntoIPCStream + Unpin + Send + 'static
+    {
+        //cleanup before connect
+        Self::reap(&_self);
+
+        tokio::spawn(async move {
+            Self::try_connect(_self, socket).await
+        });
+    }
+
     pub async fn daemon(_self:Arc<Mutex<Self>>)
     {
-        let sock_addr = {
+        let (server_addr, reap_interval, mut stop_rx) = {
             if let Ok(self_obj) = _self.lock() {
-                Some(SocketAddr::new( "127.0.0.1".parse().unwrap(), self_obj.server_port ))
+                Some(( self_obj.server_addr.clone(), self_obj.config.reap_interval, self_obj.stop_rx.clone() ))
             } else {None}
         }.unwrap();
-        let listener = TcpListener::bind(sock_addr).await.unwrap();
+        let mut reaper = tokio::time::interval(reap_interval);
 
-        loop {
-            let (socket, _) = listener.accept().await.unwrap();
-            let _self = _self.clone();
+        match server_addr {
+            ServerAddr::Tcp(server_port) => {
+                let sock_addr = SocketAddr::new( "127.0.0.1".parse().unwrap(), server_port );
+                let listener = TcpListener::bind(sock_addr).await.unwrap();
 
-            //cleanup (the first stopped) before connect
-            if let Ok(mut _self) = _self.lock() {
-                if let Some(pos) = _self.conns.iter().position(|x| !x.is_alive()) {
-                    _self.conns.remove(pos);
+                loop {
+                    tokio::select! {
+                        _ = stop_rx.changed() => break,
+                        _ = reaper.tick() => Self::reap(&_self),
+                        accepted = listener.accept() => match accepted {
+                            Ok((socket, _)) => Self::spawn_connect(_self.clone(), socket),
+                            Err(e) => eprintln!("Failed to accept: {:?}", e)
+                        }
+                    }
                 }
-            }
+            },
+            ServerAddr::Unix(path) => {
+                if let Err(e) = prepare_socket_path(&path) {
+                    eprintln!("Unable to bind unix socket: {}", e);
+                    return
+                }
+                let listener = UnixListener::bind(&path).unwrap();
+                let euid = unsafe{ libc::geteuid() };
 
-            tokio::spawn(async move {
-                Self::try_connect(_self, socket).await
-            });
+                loop {
+                    let socket = tokio::select! {
+                        _ = stop_rx.changed() => break,
+                        _ = reaper.tick() => { Self::reap(&_self); continue },
+                        accepted = listener.accept() => match accepted {
+                            Ok((socket, _)) => socket,
+                            Err(e) => { eprintln!("Failed to accept: {:?}", e); continue }
+                        }
+                    };
+                    // SO_PEERCRED: only serve the same user
+                    match socket.peer_cred() {
+                        Ok(cred) if cred.uid()==euid => {
+                            Self::spawn_connect(_self.clone(), socket);
+                        },
+                        Ok(cred) => eprintln!("Connection rejected from uid {}.", cred.uid()),
+                        Err(e) => eprintln!("Connection rejected without peer credential: {:?}", e)
+                    }
+                }
+            }
         }
-
     }
 }
@@ -2,4 +2,10 @@ pub mod ffi;
 pub mod ipc;
 pub mod traits;
 pub mod command;
-mod service;
\ No newline at end of file
+pub mod error;
+pub mod event;
+pub mod cancel;
+pub mod codec;
+mod service;
+mod chain;
+mod host;
\ No newline at end of file
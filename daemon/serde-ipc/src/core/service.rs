@@ -3,117 +3,305 @@ extern crate libloading;
 
 use std::collections::HashMap;
 //
-use libc::{c_char};
+use libc::{c_char, c_void};
 use std::ffi::{CStr, CString};
 use pyo3::prelude::*;
-use pyo3::types::{IntoPyDict,};
+use pyo3::types::{PyBytes, PyDict, PyList};
+use libffi::middle::{self, Cif, CodePtr, Type};
+use libffi::raw::ffi_arg;
+use vdm_capability_abi::{VdmBytes, VdmCapability, ABI_VERSION};
 //
-use crate::core::ffi::{Metadata, MetaFunc};
-
-type PyFuncName = String;
-type PyLibCode = String;
-
-enum RawFunc<'a,T,R>
-{
-    Value0(libloading::Symbol<'a, extern fn()->R>),
-    Value1(libloading::Symbol<'a, extern fn(T)->R>),
-    Value2(libloading::Symbol<'a, extern fn(T,T)->R>),
-    Value3(libloading::Symbol<'a, extern fn(T,T,T)->R>),
-    Value4(libloading::Symbol<'a, extern fn(T,T,T,T)->R>),
-    Value5(libloading::Symbol<'a, extern fn(T,T,T,T,T)->R>)
+use crate::core::ffi::{Metadata, MetaFunc, Arg, ArgType};
+use crate::core::error::{ErrorCode, IPCError, IPCResult};
+use crate::core::event::Emitter;
+use crate::core::cancel::CallToken;
+use crate::core::host::{self, Host, Frame, PyEmitter, VdmHost};
+
+/// native export required by the functions with `owned` result: `void vdm_free(void *ptr)`
+const FREE_SYMBOL:&[u8] = b"vdm_free";
+
+/// optional rust export (see `vdm-capability-macros`): `const VdmCapability *vdm_capability_entry(void)`
+const CAPABILITY_ENTRY_SYMBOL:&[u8] = b"vdm_capability_entry";
+
+/// `void emit(void *ctx, const char *event, const char *data)`
+type EmitFunc = extern "C" fn(*mut c_void, *const c_char, *const c_char);
+/// optional native export: `void vdm_set_emitter(void *ctx, EmitFunc emit)`
+const SET_EMITTER_SYMBOL:&[u8] = b"vdm_set_emitter";
+/// optional native export: `void vdm_set_host(const VdmHost *host)`
+const SET_HOST_SYMBOL:&[u8] = b"vdm_set_host";
+
+extern "C" fn emit_trampoline(ctx:*mut c_void, event:*const c_char, data:*const c_char) {
+    if ctx.is_null() || event.is_null() || data.is_null() {
+        return;
+    }
+    let emitter = unsafe{ &*(ctx as *const Emitter) };
+    let (event, data) = unsafe{ (CStr::from_ptr(event), CStr::from_ptr(data)) };
+    emitter.emit( &event.to_string_lossy(), &data.to_string_lossy() );
+}
+
+/// Injected into python capabilities:
+/// - `vdm_emit(event, data)`: emit the event at once, from any thread;
+/// - `vdm_cancelled()`: whether the ongoing call on the current thread is cancelled or expired, to stop cooperatively;
+/// - `vdm.register(name)`, `vdm.unregister(sig)`, `vdm.execute(sig, func, *args)`: call other capabilities.
+const PY_HOST_CODE:&str = "
+import threading as _vdm_threading
+_vdm_local = _vdm_threading.local()
+def vdm_emit(event, data): vdm.emit(_vdm_emitter, str(event), str(data))
+def vdm_cancelled(): return len(getattr(_vdm_local, 'cancel', ())) > 0
+";
+
+/// One argument or result converted as declared in metadata.
+enum Value {
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Str(String),
+    Bytes(Vec<u8>)
 }
 
-impl<'a,T,R> RawFunc<'a,T,R> {
-    pub fn load<'lib>(lib:&'lib libloading::Library, name:&[u8], argc:usize) -> Option<RawFunc<'lib,T,R>> {
-        match argc {
-            0 => Some( RawFunc::Value0( unsafe{ lib.get(name).ok()? } ) ),
-            1 => Some( RawFunc::Value1( unsafe{ lib.get(name).ok()? } ) ),
-            2 => Some( RawFunc::Value2( unsafe{ lib.get(name).ok()? } ) ),
-            3 => Some( RawFunc::Value3( unsafe{ lib.get(name).ok()? } ) ),
-            4 => Some( RawFunc::Value4( unsafe{ lib.get(name).ok()? } ) ),
-            5 => Some( RawFunc::Value5( unsafe{ lib.get(name).ok()? } ) ),
-            _ => None
+impl Value {
+    fn from_arg(arg:Arg, ty:ArgType) -> Result<Self, IPCError> {
+        let mismatch = |arg:&str| {
+            IPCError::new(ErrorCode::ArgumentMismatch, format!("'{}' given as {} argument.", arg, ty.name()))
+        };
+        match (ty, arg) {
+            (ArgType::Int, Arg::Int(v)) => Ok( Value::Int(v) ),
+            (ArgType::Float, Arg::Float(v)) => Ok( Value::Float(v) ),
+            (ArgType::Bool, Arg::Bool(v)) => Ok( Value::Bool(v) ),
+            // typed results chained into other steps, in their string form
+            (ty, arg @ Arg::Int(_)) | (ty, arg @ Arg::Float(_)) | (ty, arg @ Arg::Bool(_)) => {
+                Value::from_arg(arg.into_untyped(), ty)
+            },
+            (ArgType::Bytes, arg) => Ok( Value::Bytes(arg.into_bytes()) ),
+            (_, Arg::Bytes(_)) => Err( mismatch("bytes") ),
+            (ArgType::Str, Arg::Str(s)) => Ok( Value::Str(s) ),
+            (ArgType::Int, Arg::Str(s)) => s.trim().parse().map( Value::Int ).or( Err(mismatch(&s)) ),
+            (ArgType::Float, Arg::Str(s)) => s.trim().parse().map( Value::Float ).or( Err(mismatch(&s)) ),
+            (ArgType::Bool, Arg::Str(s)) => match s.trim() {
+                "true" => Ok( Value::Bool(true) ),
+                "false" => Ok( Value::Bool(false) ),
+                _ => Err( mismatch(&s) )
+            }
         }
     }
 
-    pub fn call(&self, mut args:Vec<T>) -> Option<R>{
-        let mut args = args.drain(..);
+    fn into_arg(self) -> Arg {
         match self {
-            Self::Value0(func) => Some(
-                func()
-            ),
-            Self::Value1(func) => Some(
-                func( args.next()? )
-            ),
-            Self::Value2(func) => Some(
-                func( args.next()?, args.next()? )
-            ),
-            Self::Value3(func) => Some(
-                func( args.next()?, args.next()?, args.next()? )
-            ),
-            Self::Value4(func) => Some(
-                func( args.next()?, args.next()?, args.next()?, args.next()? )
-            ),
-            Self::Value5(func) => Some(
-                func( args.next()?, args.next()?, args.next()?, args.next()?, args.next()? )
-            )
+            Value::Int(v) => Arg::Int(v),
+            Value::Float(v) => Arg::Float(v),
+            Value::Bool(v) => Arg::Bool(v),
+            Value::Str(v) => Arg::Str(v),
+            Value::Bytes(v) => Arg::Bytes(v)
         }
     }
 }
 
-enum Func<'a> {
-    CFunc(RawFunc<'a,*const c_char, *const c_char>),
-    RustFunc(RawFunc<'a,String, String>),
-    PythonFunc((&'a PyLibCode, PyFuncName))
+/// The C value of one argument, addressed by libffi during the call.
+enum Slot {
+    Int(i64),
+    Float(f64),
+    Bool(u8),
+    Ptr(*const c_char),
+    Bytes(VdmBytes)
 }
 
-impl<'a> Func<'a> {
-    pub fn new<'lib>(lib:&'lib LibraryContext, name:&String, argc:usize) -> Option<Func<'lib>> {
-        match lib {
-            LibraryContext::CDLL(lib) => {
-                if let Some(func) = RawFunc::load(lib, name.as_bytes(), argc) {
-                    Some( Func::CFunc(func) )
-                } else {None}
+/// `int` -> `int64_t`, `float` -> `double`, `bool` -> `bool`, `bytes` -> `VdmBytes`, otherwise `const char *`.
+fn _ffi_type(ty:ArgType) -> Type {
+    match ty {
+        ArgType::Int => Type::i64(),
+        ArgType::Float => Type::f64(),
+        ArgType::Bool => Type::u8(),
+        ArgType::Str => Type::pointer(),
+        ArgType::Bytes => Type::structure( vec![Type::pointer(), Type::usize()] )
+    }
+}
+
+/// The native function called through libffi, with the C types of its declared arguments and result.
+/// The string or bytes result is borrowed, unless declared `owned` and released by `free` once copied.
+/// The symbols are valid as long as the library is loaded.
+struct RawFunc {
+    func: unsafe extern "C" fn(),
+    cif: Cif,
+    restype: ArgType,
+    free: Option<extern "C" fn(*mut c_void)>
+}
+
+impl RawFunc {
+    pub fn load(lib:&libloading::Library, name:&str, meta:&MetaFunc) -> Result<RawFunc, IPCError> {
+        let func = *unsafe{ lib.get(name.as_bytes()) }.or(
+            Err(IPCError::new(ErrorCode::UnknownFunction, format!("'{}' not found in library.", name)))
+        )?;
+        let free = if meta.owned {
+            Some( *unsafe{ lib.get(FREE_SYMBOL) }.or(
+                Err(IPCError::new(ErrorCode::UnknownFunction, format!("'{}' returns owned result but 'vdm_free' not found in library.", name)))
+            )? )
+        } else { None };
+        let cif = Cif::new( meta.arg_types().into_iter().map(_ffi_type), _ffi_type(meta.res_type()) );
+        Ok( RawFunc{ func, cif, restype:meta.res_type(), free } )
+    }
+
+    fn release(&self, ptr:*const c_void) {
+        if let Some(free) = self.free {
+            free(ptr as *mut c_void);
+        }
+    }
+
+    pub fn call(&self, args:Vec<Value>) -> Result<Value, IPCError> {
+        let callee_failure = |e:&str| IPCError::new(ErrorCode::CalleeFailure, e);
+        // keep the strings and bytes alive until the call returns
+        let strings = args.iter().map(|arg| match arg {
+            Value::Str(s) => CString::new( s.as_str() ).map(Some),
+            _ => Ok(None)
+        }).collect::<Result<Vec<_>,_>>()
+        .or( Err(callee_failure("argument contains nul byte.")) )?;
+        let slots:Vec<Slot> = args.iter().zip(&strings).map(|(arg, s)| match (arg, s) {
+            (_, Some(s)) => Slot::Ptr( s.as_ptr() ),
+            (Value::Int(v), _) => Slot::Int(*v),
+            (Value::Float(v), _) => Slot::Float(*v),
+            (Value::Bool(v), _) => Slot::Bool(*v as u8),
+            (Value::Bytes(v), _) => Slot::Bytes( VdmBytes{ ptr:v.as_ptr(), len:v.len() } ),
+            (Value::Str(_), None) => unreachable!()
+        }).collect();
+        let _args:Vec<middle::Arg> = slots.iter().map(|slot| match slot {
+            Slot::Int(v) => middle::arg(v),
+            Slot::Float(v) => middle::arg(v),
+            Slot::Bool(v) => middle::arg(v),
+            Slot::Ptr(v) => middle::arg(v),
+            Slot::Bytes(v) => middle::arg(v)
+        }).collect();
+
+        let code = CodePtr::from_fun(self.func);
+        unsafe{ match self.restype {
+            ArgType::Int => Ok( Value::Int(self.cif.call::<i64>(code, &_args)) ),
+            ArgType::Float => Ok( Value::Float(self.cif.call::<f64>(code, &_args)) ),
+            // results narrower than a register are widened by libffi
+            ArgType::Bool => Ok( Value::Bool(self.cif.call::<ffi_arg>(code, &_args) as u8 != 0) ),
+            ArgType::Str => {
+                let res = self.cif.call::<*const c_char>(code, &_args);
+                if res.is_null() {
+                    return Err( callee_failure("null string returned.") );
+                }
+                let value = CStr::from_ptr(res).to_string_lossy().into_owned();
+                self.release(res as *const c_void);
+                Ok( Value::Str(value) )
             },
-            LibraryContext::Rust(lib) => {
-                if let Some(func) = RawFunc::load(lib, name.as_bytes(), argc) {
-                    Some( Func::RustFunc(func) )
-                } else {None}
+            ArgType::Bytes => {
+                let res = self.cif.call::<VdmBytes>(code, &_args);
+                if res.ptr.is_null() {
+                    return match res.len {
+                        0 => Ok( Value::Bytes(Vec::new()) ),
+                        _ => Err( callee_failure("null bytes returned.") )
+                    };
+                }
+                let value = std::slice::from_raw_parts(res.ptr, res.len).to_vec();
+                self.release(res.ptr as *const c_void);
+                Ok( Value::Bytes(value) )
             }
-            LibraryContext::Python(lib) => {
-                Some( Func::PythonFunc(( &lib, name.clone() )) )
+        } }
+    }
+}
+
+// immutable once prepared, the interface is only read by libffi during the calls
+unsafe impl Send for RawFunc {}
+unsafe impl Sync for RawFunc {}
+
+/// The functions resolved once on load, and reused across calls.
+enum Func {
+    NativeFunc(RawFunc),
+    EntryFunc((VdmCapability, CString)),
+    // (function, thread-local state of the module)
+    PythonFunc((PyObject, PyObject))
+}
+
+impl Func {
+    pub fn new(lib:&LibraryContext, name:&str, meta:&MetaFunc) -> Result<Func, IPCError> {
+        match lib {
+            LibraryContext::Rust(_, Some(entry)) => {
+                let name = CString::new(name).or(
+                    Err(IPCError::new(ErrorCode::UnknownFunction, format!("'{}' contains nul byte.", name)))
+                )?;
+                if !(entry.has)(name.as_ptr()) {
+                    return Err( IPCError::new(ErrorCode::UnknownFunction,
+                        format!("'{}' not exported by capability.", name.to_string_lossy())) );
+                }
+                Ok( Func::EntryFunc(( *entry, name )) )
+            },
+            LibraryContext::CDLL(lib) | LibraryContext::Rust(lib, None) => {
+                RawFunc::load(lib, name, meta).map( Func::NativeFunc )
+            },
+            LibraryContext::Python(module) => {
+                Python::with_gil(|py| {
+                    let module = module.as_ref(py);
+                    let func = module.getattr(name).or(
+                        Err(IPCError::new(ErrorCode::UnknownFunction, format!("'{}' not found in module.", name)))
+                    )?;
+                    let local = module.getattr("_vdm_local").or(
+                        Err(IPCError::new(ErrorCode::CalleeFailure, "host code missing in module."))
+                    )?;
+                    Ok( Func::PythonFunc(( func.into(), local.into() )) )
+                })
             }
         }
     }
 
-    pub fn call(&self, args:Vec<String>, args_name:Vec<&String>) -> Option<String> {
+    pub fn call(&self, args:Vec<Value>, args_name:Vec<&String>, restype:ArgType, token:&CallToken) -> IPCResult {
+        let callee_failure = |e:String| IPCError::new(ErrorCode::CalleeFailure, e);
         match self {
-            Self::CFunc(func) => {
-                let args:Vec<CString> = args.iter().map(|arg|{
-                    CString::new( arg.to_string() ).unwrap()
-                }).collect();
-                let _args:Vec<*const c_char> = args.iter().map(|arg|{
-                    arg.as_ptr()
-                }).collect();
-                unsafe{ Some(
-                    CStr::from_ptr( func.call(_args)? ).to_string_lossy().into_owned()
-                ) }
+            Self::NativeFunc(func) => {
+                func.call(args).map( Value::into_arg )
             },
-            Self::RustFunc(func) => {
-                func.call(args)
+            Self::EntryFunc((entry, name)) => {
+                let args:Vec<Vec<u8>> = args.into_iter().map(|arg| arg.into_arg().into_bytes()).collect();
+                let _args:Vec<VdmBytes> = args.iter().map(|arg|{
+                    VdmBytes{ ptr:arg.as_ptr(), len:arg.len() }
+                }).collect();
+                let mut res = VdmBytes{ ptr:std::ptr::null(), len:0 };
+                let code = (entry.call)(name.as_ptr(), _args.as_ptr(), _args.len(), &mut res);
+                let res = if res.ptr.is_null() { Vec::new() } else {
+                    let data = unsafe{ std::slice::from_raw_parts(res.ptr, res.len).to_vec() };
+                    (entry.free_bytes)(res);
+                    data
+                };
+                if code != 0 {
+                    return Err( callee_failure( String::from_utf8_lossy(&res).into_owned() ) );
+                }
+                let res = if restype==ArgType::Bytes { Arg::Bytes(res) } else {
+                    Arg::Str( String::from_utf8_lossy(&res).into_owned() )
+                };
+                Value::from_arg(res, restype).map( Value::into_arg ).map_err(|e| callee_failure(e.message))
             },
-            Self::PythonFunc(func) => {
-                let (lib, func) = func;
+            Self::PythonFunc((py_func, py_local)) => {
                 Python::with_gil(|py|{
-                    let py_module = PyModule::from_code(py, lib, "__internal__.py", "__internal__").ok()?;
-                    let py_func = py_module.get(func).ok()?;
-                    let kwargs:Vec<(String,String)> = args.into_iter().enumerate().map(|(i,v)|{
-                        ( args_name[i].to_string(), v )
-                    }).collect();
-                    let kwargs = kwargs.into_py_dict(py);
-                    
-                    py_func.call((), Some(kwargs)).ok()?.extract().ok()
-                })?
+                    // the cancellation of this call, seen by the host code on this thread
+                    let py_cancel = PyList::empty(py);
+                    py_local.as_ref(py).setattr("cancel", py_cancel)
+                        .map_err(|e| callee_failure( e.to_string() ))?;
+                    let py_cancel:Py<PyList> = py_cancel.into();
+                    token.on_interrupt(Box::new(move || {
+                        Python::with_gil(|py| py_cancel.as_ref(py).append(true).unwrap_or(()));
+                    }));
+                    let kwargs = PyDict::new(py);
+                    for (i, v) in args.into_iter().enumerate() {
+                        match v {
+                            Value::Int(v) => kwargs.set_item(args_name[i], v),
+                            Value::Float(v) => kwargs.set_item(args_name[i], v),
+                            Value::Bool(v) => kwargs.set_item(args_name[i], v),
+                            Value::Str(v) => kwargs.set_item(args_name[i], v),
+                            Value::Bytes(v) => kwargs.set_item(args_name[i], PyBytes::new(py, &v))
+                        }.map_err(|e| callee_failure( e.to_string() ))?;
+                    }
+
+                    py_func.as_ref(py).call((), Some(kwargs)).map_err(|e| callee_failure( e.to_string() ))
+                        .and_then(|res| {
+                            match restype {
+                                ArgType::Int => res.extract::<i64>().map( Value::Int ),
+                                ArgType::Float => res.extract::<f64>().map( Value::Float ),
+                                ArgType::Bool => res.extract::<bool>().map( Value::Bool ),
+                                ArgType::Str => res.extract::<String>().map( Value::Str ),
+                                ArgType::Bytes => res.extract::<&[u8]>().map( |b| Value::Bytes(b.to_vec()) )
+                            }.map( Value::into_arg ).map_err(|e| callee_failure( e.to_string() ))
+                        })
+                })
             }
         }
     }
@@ -121,49 +309,142 @@ impl<'a> Func<'a> {
 
 enum LibraryContext {
     CDLL(libloading::Library),
-    Rust(libloading::Library),
-    Python(PyLibCode)
+    // with the vtable, if exported
+    Rust(libloading::Library, Option<VdmCapability>),
+    Python(Py<PyModule>)
+}
+
+/// Import the capability once as module `name`, with the host code injected.
+fn _load_python(entry:&str, name:&str, emitter:&PyEmitter) -> Result<Py<PyModule>, String> {
+    let code = std::fs::read_to_string(entry).or( Err(format!("'{}' not readable.", entry)) )?;
+    Python::with_gil(|py| {
+        let module = PyModule::new(py, name)?;
+        module.add("vdm", host::py_module(py)?)?;
+        module.add("_vdm_emitter", emitter.id())?;
+        py.run(PY_HOST_CODE, Some(module.dict()), None)?;
+        module.add("__file__", entry)?;
+        py.run(&code, Some(module.dict()), None)?;
+        Ok( module.into() )
+    }).map_err(|e:PyErr| format!("module load failed: {}", e))
 }
 
 pub struct Service {
-    context: LibraryContext,
-    func: HashMap<String, MetaFunc>
+    // resolved from "_context", and dropped before it
+    funcs: HashMap<String, Func>,
+    // kept loaded as long as the service
+    _context: LibraryContext,
+    func: HashMap<String, MetaFunc>,
+    // boxed for a stable address handed to native code; dropped after "_context"
+    _emitter: Box<Emitter>,
+    // reached by `vdm_emit` of python capabilities
+    _py_emitter: Option<PyEmitter>,
+    host: Box<Host>,
+    _host_table: Box<VdmHost>
 }
 
 impl Service {
-    pub fn load(entry:&String, metadata:Metadata) -> Option<Self> {
+    /// Load the library and resolve all the functions declared in metadata, failing with the reason.
+    pub fn load(entry:&String, metadata:Metadata, emitter:Emitter, host:Host) -> Result<Self, String> {
+        let open = |entry:&String| unsafe{ libloading::Library::new(entry) }.map_err(|e| e.to_string());
+        let mut py_emitter = None;
         let context = match &metadata.class[..] {
             "c" | "cpp" => {
-                if let Ok(lib) = unsafe{ libloading::Library::new(entry) } {
-                    Some( LibraryContext::CDLL(lib) )
-                } else { None }
+                LibraryContext::CDLL( open(entry)? )
             },
             "rust" => {
-                if let Ok(lib) = unsafe{ libloading::Library::new(entry) } {
-                    Some( LibraryContext::Rust(lib) )
-                } else { None }
+                let lib = open(entry)?;
+                // called like C libraries without `vdm_capability_entry`; refused with another version
+                let capability = unsafe{ lib.get::<extern "C" fn()->*const VdmCapability>(CAPABILITY_ENTRY_SYMBOL) }
+                                .ok().map(|entry| entry());
+                match capability {
+                    None => LibraryContext::Rust(lib, None),
+                    Some(ptr) if ptr.is_null() => return Err( "'vdm_capability_entry' returned null.".into() ),
+                    Some(ptr) => {
+                        let capability = unsafe{ *ptr };
+                        if capability.abi_version != ABI_VERSION {
+                            return Err( format!("capability ABI version {} not supported.", capability.abi_version) );
+                        }
+                        LibraryContext::Rust(lib, Some(capability))
+                    }
+                }
             }
             "python" => {
-                if let Ok(contents) = std::fs::read_to_string(entry) {
-                    Some( LibraryContext::Python(contents) )
-                } else { None }
+                let _py_emitter = py_emitter.insert( PyEmitter::register(emitter.clone()) );
+                LibraryContext::Python( _load_python(entry, &metadata.name, _py_emitter)? )
             },
-            _ => { None }
+            class => return Err( format!("class '{}' not supported.", class) )
         };
         let func = metadata.func;
+        let funcs = func.iter().map(|(name, meta)| {
+            Func::new(&context, name, meta).map(|f| (name.clone(), f)).map_err(|e| e.message)
+        }).collect::<Result<_,_>>()?;
+        let emitter = Box::new(emitter);
+        let host = Box::new(host);
+        let host_table = Box::new( VdmHost::new(&host) );
+
+        // hand the emitter to native capabilities exporting `vdm_set_emitter`
+        if let LibraryContext::CDLL(lib) | LibraryContext::Rust(lib, _) = &context {
+            let set_emitter = unsafe{ lib.get::<extern "C" fn(*mut c_void, EmitFunc)>(SET_EMITTER_SYMBOL) };
+            if let Ok(set_emitter) = set_emitter {
+                set_emitter(&*emitter as *const Emitter as *mut c_void, emit_trampoline);
+            }
+            // and the host callbacks to those exporting `vdm_set_host`
+            let set_host = unsafe{ lib.get::<extern "C" fn(*const VdmHost)>(SET_HOST_SYMBOL) };
+            if let Ok(set_host) = set_host {
+                set_host(&*host_table);
+            }
+        }
+        Ok(
+            Service{ funcs, _context:context, func, _emitter:emitter, _py_emitter:py_emitter, host, _host_table:host_table }
+        )
+    }
+
+    pub fn spec(&self) -> &HashMap<String, MetaFunc> {
+        &self.func
+    }
+
+    pub fn host(&self) -> &Host {
+        &self.host
+    }
+
+    pub fn call(&self, name:&String, args:Vec<Arg>, token:&CallToken) -> IPCResult {
+        let (func, meta) = match (self.funcs.get(name), self.func.get(name)) {
+            (Some(func), Some(meta)) => (func, meta),
+            _ => return Err( IPCError::new(ErrorCode::UnknownFunction, format!("'{}' not declared in metadata.", name)) )
+        };
+        let argc = meta.args.len();
+        if args.len() != argc {
+            return Err( IPCError::new(ErrorCode::ArgumentMismatch,
+                format!("'{}' takes {} argument(s) but {} given.", name, argc, args.len())) );
+        }
+        let args_name:Vec<&String> = meta.args.iter().map (|(a1,_)|{a1} ).collect();
+        let args = args.into_iter().zip( meta.arg_types() ).map(|(arg, ty)| {
+            Value::from_arg(arg, ty)
+        }).collect::<Result<_,_>>()?;
+        let _frame = Frame::enter(&self.host, token);
+
+        func.call(args, args_name, meta.res_type(), token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        if let Some(context) = context {
-            Some(
-                Service{ context, func }
-            )
-        } else {None}
+    #[test]
+    fn typed_results_stay_typed() {
+        assert_eq!( Value::Int(3).into_arg(), Arg::Int(3) );
+        assert_eq!( Value::Float(0.5).into_arg(), Arg::Float(0.5) );
+        assert_eq!( Value::Bool(true).into_arg(), Arg::Bool(true) );
+        assert_eq!( Value::Str("3".into()).into_arg(), Arg::Str("3".into()) );
     }
 
-    pub fn call(&self, name:&String, args:Vec<String>) -> Option<String> {
-        let func = self.func.get(name)?;
-        let argc = func.args.len();
-        let args_name:Vec<&String> = func.args.iter().map (|(_,a2)|{a2} ).collect();
-        
-        Func::new(&self.context, name, argc)?.call(args, args_name)
+    #[test]
+    fn chained_results_convert_by_string_form() {
+        assert!( matches!(Value::from_arg(Arg::Int(3), ArgType::Int), Ok(Value::Int(3))) );
+        assert!( matches!(Value::from_arg(Arg::Int(3), ArgType::Float), Ok(Value::Float(v)) if v==3.0) );
+        assert!( matches!(Value::from_arg(Arg::Bool(true), ArgType::Str), Ok(Value::Str(ref s)) if s=="true") );
+        assert!( matches!(Value::from_arg(Arg::Float(0.5), ArgType::Bytes), Ok(Value::Bytes(ref b)) if b==b"0.5") );
+        assert!( Value::from_arg(Arg::Float(0.5), ArgType::Int).is_err() );
     }
 }
@@ -1,25 +1,30 @@
 use std::sync::{mpsc,};
+use std::time::Duration;
 //
 use crate::core::ffi::ArcFFIManager;
+use crate::core::ipc::{IPCConfig, IPCStream};
 
+/// The body format between the bytes on wire and the values dispatched.
 pub trait Serde
-// where T: Into<Self::Value> + Clone
 {
-    type Value: Into<Self::Value> + Clone;
-    
-    fn to_raw_data(v:&Self::Value) -> Option<String>;
-    fn from_raw_data<T>(r:&T) -> Option<Self::Value>
-    where T: Into<Self::Value> + Clone;
+    type Value;
+
+    fn to_raw_data(v:&Self::Value) -> Result<Vec<u8>, String>;
+    fn from_raw_data(r:&[u8]) -> Result<Self::Value, String>;
 }
 
 pub trait IPCProtocol: Sync+Send+Clone+'static
 {
     type Message: Send;
 
-    fn new(uid:String, ffi:ArcFFIManager) -> Self;
+    /// Create the connection; `stream` is the handshake socket if framing over it is negotiated.
+    fn new(uid:String, config:IPCConfig, ffi:ArcFFIManager, stream:Option<IPCStream>) -> Self;
     //
     fn is_alive(&self) -> bool;
     fn spawn_send_thread(&mut self, rx: mpsc::Receiver<Self::Message>);
     fn spawn_recv_thread(&mut self, tx: mpsc::Sender<Self::Message>);
+    /// Signal both threads to exit; they release the usages and unlink the resources.
     fn stop(&self);
+    /// Wait for both threads to exit, return `false` on timeout.
+    fn join(&self, timeout:Duration) -> bool;
 }
\ No newline at end of file
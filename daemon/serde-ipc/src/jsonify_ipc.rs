@@ -9,53 +9,39 @@ use serde_json::{self, Value as JsonValue};
 // root crates
 use crate::core::ipc;
 use crate::core::ffi;
-use crate::core::traits::{Serde, IPCProtocol};
+use crate::core::traits::IPCProtocol;
 use crate::core::command::ExecResult;
 
 pub struct JsonifyIPC<P>
 where P: IPCProtocol
 {
     // root: PathBuf,
-    server_port: u16,
+    server_addr: ipc::ServerAddr,
+    config: ipc::IPCConfig,
     rt: TokioRuntime,
     ffi: ffi::ArcFFIManager,
     server: Option<Arc<Mutex<ipc::IPCServer<P>>>>
 }
 
-impl Serde for ffi::FFIManager
-{
-    type Value = JsonValue;
-
-    fn to_raw_data(v:&JsonValue) -> Option<String> {
-        serde_json::to_string(v).ok()
-    }
-
-    fn from_raw_data<T>(r:&T) -> Option<JsonValue> 
-    where T: Into<JsonValue> + Clone
-    {
-        Some( r.clone().into() )
-        // serde_json::from_str(r).ok()
-    }
-}
-
 impl<P> JsonifyIPC<P>
 where P: IPCProtocol
 {
     /// Return JsonifyIPC handle configured with given:
     /// - (Optional) **path**: the working directory for capability, default is `~/.vdm/libs`
-    pub fn new(root:Option<String>, server_port:Option<u16>) -> Self {
+    /// - (Optional) **server_addr**: the handshake address, default is the per-user unix socket
+    /// - (Optional) **config**: the transport limits and compression threshold, default is `IPCConfig::default()`
+    pub fn new(root:Option<String>, server_addr:Option<ipc::ServerAddr>, config:Option<ipc::IPCConfig>) -> Self {
         let root = PathBuf::from(
             root.unwrap_or( expand_user("~/.serde_ipc").into_owned() )
         );
-        let server_port = server_port.unwrap_or(42000);
+        let server_addr = server_addr.unwrap_or_default();
+        let config = config.unwrap_or_default();
 
         let rt = TokioRuntime::new().unwrap();
-        let ffi = Arc::new(Mutex::new(
-            ffi::FFIManager::new(root)
-        ));
+        let ffi = ffi::FFIManager::new(root).into_shared();
         
         JsonifyIPC {
-            server_port, rt, ffi, server:None
+            server_addr, config, rt, ffi, server:None
         }
     }
 
@@ -64,7 +50,7 @@ where P: IPCProtocol
         let ffi = self.ffi.clone();
 
         self.server = Some( ipc::IPCServer::<P>::new(
-            self.server_port, ffi
+            self.server_addr.clone(), self.config.clone(), ffi
         ) );
 
         let _server = self.server.clone();
@@ -73,12 +59,14 @@ where P: IPCProtocol
         });
     }
 
-    /// Stop the JsonifyIPC daemon by: 1) shutdown all tokio threads; 2) stop IPCServer thread pool.
-    pub fn stop(mut self) {
-        self.rt.shutdown_background(); //drop occurs here
-        self.server = None; //drop occurs here
-        //
-        self.rt = TokioRuntime::new().unwrap();
+    /// Stop the JsonifyIPC daemon by: 1) stop accepting and stop all connections; 2) shutdown all tokio threads.
+    pub fn stop(self) {
+        if let Some(server) = self.server {
+            if !ipc::IPCServer::shutdown( server, self.config.shutdown_timeout ) {
+                eprintln!("Some connections failed to exit in {:?}.", self.config.shutdown_timeout);
+            }
+        }
+        self.rt.shutdown_timeout( self.config.shutdown_timeout );
     }
 }
 
@@ -123,7 +111,9 @@ where P: IPCProtocol
                     Some( (k.clone(), v.clone()) )
                 }).collect();
 
-                let metafunc = ffi::MetaFunc{ restype, args:args? };
+                let owned = info.get("owned").and_then( |val|{val.as_bool()} ).unwrap_or(false);
+
+                let metafunc = ffi::MetaFunc{ restype, args:args?, owned };
                 metadata.func.insert( name.into(), metafunc );
             }
             Some(())
@@ -142,7 +132,7 @@ where P: IPCProtocol
     /// Get service directly via FFI Manager
     pub fn get_service(&mut self, name:String) -> Option<String> {
         let mut _ffi = self.ffi.lock().ok()?;
-        _ffi.register(&name)
+        _ffi.register(&name).ok()
     }
 
     /// Destroy service directly via FFI Manager
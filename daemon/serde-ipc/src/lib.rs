@@ -1,8 +1,15 @@
 mod core;
 
 // export core interface
-pub use crate::core::traits::{IPCProtocol,};
-pub use crate::core::ffi::{FFIDescriptor, FFIManager, ArcFFIManager};
+pub use crate::core::traits::{IPCProtocol, Serde};
+pub use crate::core::ipc::{IPCConfig, IPCStream, ServerAddr, PROTOCOL_VERSION, MIN_PROTOCOL_VERSION};
+pub use crate::core::ipc::{FEATURE_CHUNKING, FEATURE_COMPRESSION, FEATURE_BINARY, FEATURE_PUSH_EVENTS, FEATURE_SOCKET_TRANSPORT};
+pub use crate::core::ipc::{FEATURE_MSGPACK, FEATURE_CBOR, FEATURE_LZ4};
+pub use crate::core::ffi::{FFIDescriptor, FFIManager, ArcFFIManager, MetaFunc, Arg, ArgType, BYTES_TYPE};
+pub use crate::core::error::{ErrorCode, IPCError, IPCResult};
+pub use crate::core::event::{EventBus, EventSink, Emitter};
+pub use crate::core::cancel::CallToken;
+pub use crate::core::codec::{Codec, Compression, Json, MsgPack, Cbor};
 
 // export JsonifyIPC implementation
 mod jsonify_ipc;
@@ -1,17 +1,65 @@
 #!/usr/bin/env python3
 import random, time, string
 import os, re, socket, struct, signal
-import json, base64
+import json, base64, threading
 from enum import Enum
 from functools import wraps
+from queue import Empty
 from multiprocessing import (Process, Value, Queue)
 from multiprocessing.shared_memory import SharedMemory
 from posix_ipc import (Semaphore, O_CREX)
+try:
+    import msgpack
+except ImportError:
+    msgpack = None
+try:
+    import cbor2
+except ImportError:
+    cbor2 = None
+try:
+    import zstandard
+except ImportError:
+    zstandard = None
+try:
+    import lz4.block
+except ImportError:
+    lz4 = None
 
 SHM_REQ_MAX_SIZE = 10*1024   #10KB
 SHM_RES_MAX_SIZE = 1024*1024 #1MB
-VDM_SERVER_PORT = 42000
 VDM_CLIENT_ID_LEN = 16
+FLAG_MORE = 0x0001 #more chunks of the same seq follow
+FLAG_EVENT = 0x0002 #unsolicited event of subscription
+FLAG_BINARY = 0x0004 #body is [json_len, json, blobs], with bytes referred as {'$blob':[offset, len]}
+FLAG_COMPRESSED = 0x0008 #body is compressed with the negotiated compression
+COMPRESS_THRESHOLD = 16*1024 #16KB
+HEARTBEAT_SEQ = 0 #the daemon drops idle peers after 30s
+HEARTBEAT_INTERVAL = 10
+#
+PROTOCOL_VERSION    = 1
+FEATURE_CHUNKING    = 0x0001
+FEATURE_COMPRESSION = 0x0002 #zstd
+FEATURE_BINARY      = 0x0004
+FEATURE_PUSH_EVENTS = 0x0008
+FEATURE_SOCKET_TRANSPORT = 0x0010
+FEATURE_MSGPACK     = 0x0020
+FEATURE_CBOR        = 0x0040
+FEATURE_LZ4         = 0x0080
+SUPPORTED_FEATURES  = FEATURE_CHUNKING | FEATURE_BINARY | FEATURE_PUSH_EVENTS \
+                        | (FEATURE_MSGPACK if msgpack else 0) | (FEATURE_CBOR if cbor2 else 0) \
+                        | (FEATURE_COMPRESSION if zstandard else 0) | (FEATURE_LZ4 if lz4 else 0)
+RING_CTRL   = struct.Struct('=QQ')     #['head':8B, 'tail':8B], monotonic byte counters
+RING_CTRL_LEN = 64
+REQ_HEADER  = struct.Struct('=IHHI')   #['seq':4B, 'command':2B, 'flags':2B, 'size':4B]
+RES_HEADER  = struct.Struct('=IHI')    #['seq':4B, 'flags':2B, 'size':4B]
+BIN_PREFIX  = struct.Struct('=I')      #['json_len':4B]
+HS_HELLO    = struct.Struct('=%dsHI'%VDM_CLIENT_ID_LEN)     #[id, version, features]
+HS_WELCOME  = struct.Struct('=%dsHIHH'%VDM_CLIENT_ID_LEN)   #[id, version, features, status, msg_len]
+VDM_SERVER_PORT = 42000 #only used with TCP opt-in
+VDM_SERVER_SOCK = os.path.join(
+    os.path.join(os.environ['XDG_RUNTIME_DIR'], 'vdm') if 'XDG_RUNTIME_DIR' in os.environ else '/tmp/vdm-%d'%os.geteuid(),
+    'capability.sock'
+)
 GET_RANDOM_ID = lambda: ''.join( random.choices(string.hexdigits, k=VDM_CLIENT_ID_LEN) )
 
 class _COMMAND(Enum): #2-byte
@@ -21,6 +69,32 @@ class _COMMAND(Enum): #2-byte
     CALL        = 0x03
     ONE_WAY     = 0x04
     CHAIN_CALL  = 0x05
+    SUBSCRIBE   = 0x06
+    UNSUBSCRIBE = 0x07
+    CANCEL      = 0x08
+    BATCH       = 0x09
+    pass
+
+class _ERRCODE(Enum): #2-byte
+    UNKNOWN_COMMAND     = 0x01
+    MALFORMED_REQUEST   = 0x02
+    UNKNOWN_CAPABILITY  = 0x03
+    BAD_SIGNATURE       = 0x04
+    UNKNOWN_FUNCTION    = 0x05
+    ARGUMENT_MISMATCH   = 0x06
+    CALLEE_FAILURE      = 0x07
+    PAYLOAD_TOO_LARGE   = 0x08
+    CANCELLED           = 0x09
+    DEADLINE_EXCEEDED   = 0x0A
+    UPSTREAM_FAILED     = 0x0B
+    CAPABILITY_CYCLE    = 0x0C
+    pass
+
+class CapabilityError(Exception):
+    def __init__(self, code: _ERRCODE, message: str):
+        super().__init__('%s: %s'%(code.name, message))
+        self.code = code
+        self.message = message
     pass
 
 class AnyType(str):
@@ -37,96 +111,223 @@ class AnyType(str):
             raise Exception('Invalid value for AnyType')
     pass
 
-def __validate(_type, x) -> bool:
+def _validate(_type, x) -> bool:
     _map = {
         'Null':   lambda x:x is None,
         'Bool':   lambda x:isinstance(x, bool),
-        'Number': lambda x:isinstance(x, int) or isinstance(float),
-        'String': lambda x:isinstance(x, str) or isinstance(bytes),
+        'Number': lambda x:isinstance(x, int) or isinstance(x, float),
+        'String': lambda x:isinstance(x, str) or isinstance(x, bytes),
         'Array':  lambda x:isinstance(x, list),
-        'Object': lambda x:isinstance(x, dict)
+        'Object': lambda x:isinstance(x, dict),
+        'bytes':  lambda x:isinstance(x, bytes),
+        'int':    lambda x:isinstance(x, int) and not isinstance(x, bool),
+        'float':  lambda x:isinstance(x, int) or isinstance(x, float),
+        'bool':   lambda x:isinstance(x, bool),
+        'string': lambda x:isinstance(x, str)
     }
     _regex = re.compile('\<(.*)\>')
     #
-    _nested = _regex.search(_type)
+    _nested = _regex.split(_type)
     if isinstance(x, AnyType): #only for top-level type
         return (_type==x.restype)
     else:
         if len(_nested)==3:
-            if _nested[0]=='Array' and _map[_type](x):
-                return __validate(_nested[1], x[0])
-            elif _nested[0]=='Object' and _map[_type](x):
+            if _nested[0]=='Array' and _map[_nested[0]](x):
+                return len(x)==0 or _validate(_nested[1], x[0])
+            elif _nested[0]=='Object' and _map[_nested[0]](x):
                 key_type, val_type = _nested[1].replace(' ','').split(',')
-                return key_type=='String' and __validate(key_type, x.keys()[0]) and __validate(val_type, x.values()[0])
+                if len(x)==0:
+                    return key_type=='String'
+                _key, _val = next(iter( x.items() ))
+                return key_type=='String' and _validate(key_type, _key) and _validate(val_type, _val)
             else:
                 return False
         else:
             return _map[_type](x)
     pass
 
+def _codec(features) -> tuple:
+    """Return (dumps, loads) of the negotiated body format, JSON unless MessagePack or CBOR is."""
+    if features & FEATURE_MSGPACK:
+        return (msgpack.packb, msgpack.unpackb)
+    if features & FEATURE_CBOR:
+        return (cbor2.dumps, cbor2.loads)
+    return (lambda x:json.dumps(x).encode(), json.loads)
+
+def _compressor(features) -> tuple:
+    """Return (compress, decompress) of the negotiated compression, zstd preferred over lz4; or (None, None)."""
+    if features & FEATURE_COMPRESSION:
+        return (zstandard.ZstdCompressor().compress, zstandard.ZstdDecompressor().decompress)
+    if features & FEATURE_LZ4:
+        return (lambda x:lz4.block.compress(x, store_size=True), lz4.block.decompress)
+    return (None, None)
+
+class RingBuffer:
+    """Single-producer single-consumer ring of frames, the same layout as the daemon's."""
+    def __init__(self, shm: SharedMemory, sem_data: Semaphore, sem_space: Semaphore) -> None:
+        self.buf = shm.buf
+        self.capacity = shm.size - RING_CTRL_LEN
+        self.sem_data = sem_data   #posted once per published frame
+        self.sem_space = sem_space #posted whenever the consumer frees space
+        pass
+
+    def _head(self) -> int:
+        return RING_CTRL.unpack_from(self.buf, 0)[0]
+
+    def _tail(self) -> int:
+        return RING_CTRL.unpack_from(self.buf, 0)[1]
+
+    def push(self, frame: bytes) -> None:
+        if len(frame) > self.capacity:
+            raise CapabilityError(_ERRCODE.PAYLOAD_TOO_LARGE, 'frame exceeds ring capacity.')
+        tail = self._tail()
+        while self.capacity - (tail - self._head()) < len(frame):
+            self.sem_space.acquire() #wait until space is freed
+        # write with wrap-around, then publish
+        pos = tail % self.capacity
+        first = min(len(frame), self.capacity - pos)
+        self.buf[RING_CTRL_LEN+pos : RING_CTRL_LEN+pos+first] = frame[:first]
+        self.buf[RING_CTRL_LEN : RING_CTRL_LEN+len(frame)-first] = frame[first:]
+        struct.pack_into('=Q', self.buf, 8, tail + len(frame))
+        self.sem_data.release()
+        pass
+
+    def wait_data(self) -> None:
+        self.sem_data.acquire()
+
+    def peek(self, offset, size) -> bytes:
+        pos = (self._head() + offset) % self.capacity
+        first = min(size, self.capacity - pos)
+        return bytes( self.buf[RING_CTRL_LEN+pos : RING_CTRL_LEN+pos+first] ) + \
+               bytes( self.buf[RING_CTRL_LEN : RING_CTRL_LEN+size-first] )
+
+    def consume(self, size) -> None:
+        struct.pack_into('=Q', self.buf, 0, self._head() + size)
+        self.sem_space.release()
+        pass
+
+    pass
+
 class ShmManager:
-    def __init__(self, _id) -> None:
+    def __init__(self, _id, req_size=SHM_REQ_MAX_SIZE) -> None:
         self.req_id = _id+'_req'
         self.res_id = _id+'_res'
         #
-        self.shm_req = SharedMemory(name=self.req_id, create=True, size=SHM_REQ_MAX_SIZE)
-        self.sem_req = Semaphore('/'+self.req_id, flags=O_CREX, initial_value=1)
+        self.shm_req = SharedMemory(name=self.req_id, create=True, size=req_size)
+        self.sem_req = Semaphore('/'+self.req_id, flags=O_CREX, initial_value=0)
+        self.sem_req_ack = Semaphore('/'+self.req_id+'_ack', flags=O_CREX, initial_value=0)
         self.shm_res = None
         self.sem_res = None
+        self.sem_res_ack = None
+        self.features = 0
+        self.handlers = dict()
         pass
 
-    def send(self, q_in: Queue, shm_req: SharedMemory, sem_req: Semaphore, seq: Value) -> None:
-        #req_header: ['seq':4B, 'command':2B, 'size':2B]
-        req_header = struct.Struct('4B2B2B')
+    def send(self, q_in: Queue, shm_req: SharedMemory, sem_req: Semaphore, sem_req_ack: Semaphore) -> None:
+        req_header = REQ_HEADER
+        ring = RingBuffer(shm_req, sem_req, sem_req_ack)
+        chunk_size = ring.capacity - req_header.size
         try:
             signal.signal(signal.SIGTERM, lambda: (_ for _ in ()).throw(Exception()) )
             signal.signal(signal.SIGKILL, lambda: (_ for _ in ()).throw(Exception()) )
             while True:
-                with sem_req as sm: #acquire lock until data is ready (to send)
-                    command, data = q_in.get(timeout=15)
-                    data = bytearray( data.encode() )
-                    with seq.get_lock(): #read
-                        buffer = req_header.pack(seq.value, command, len(data))
-                        buffer += data
-                    shm_req.buf[:len(buffer)] = buffer
-                    pass
+                try:
+                    seq, command, data, flags = q_in.get(timeout=HEARTBEAT_INTERVAL)
+                except Empty:
+                    seq, command, data, flags = HEARTBEAT_SEQ, _COMMAND.ALIVE, b'', 0
+                chunks = [ data[i:i+chunk_size] for i in range(0, len(data), chunk_size) ] or [b'']
+                for i,chunk in enumerate(chunks):
+                    _flags = flags | FLAG_MORE if i+1 < len(chunks) else flags
+                    ring.push( req_header.pack(seq, command.value, _flags, len(chunk)) + chunk )
                 time.sleep(0) #transfer to other process
         except:
             self.close()
         pass
 
-    def recv(self, q_out: Queue, shm_res: SharedMemory, sem_res: Semaphore) -> None:
-        #res_header: ['seq':4B, 'size':4B]
-        res_header = struct.Struct('4B4B')
-        res_header_len = len(res_header)
+    def recv(self, q_out: Queue, q_evt: Queue, shm_res: SharedMemory, sem_res: Semaphore, sem_res_ack: Semaphore) -> None:
+        res_header = RES_HEADER
+        res_header_len = res_header.size
+        ring = RingBuffer(shm_res, sem_res, sem_res_ack)
+        pending = dict() #partially received (chunked) responses
         try:
             signal.signal(signal.SIGTERM, lambda: (_ for _ in ()).throw(Exception()) )
             signal.signal(signal.SIGKILL, lambda: (_ for _ in ()).throw(Exception()) )
             while True:
-                with sem_res as sm: #get lock once data is ready (to recv)
-                    seq, _size = res_header.unpack( shm_res.buf[:res_header_len] )
-                    buffer = shm_res.buf[res_header_len:res_header_len+_size].copy()
+                ring.wait_data() #wait until a frame is ready (to recv)
+                seq, _flags, _size = res_header.unpack( ring.peek(0, res_header_len) )
+                buffer = ring.peek(res_header_len, _size)
+                ring.consume(res_header_len + _size) #free the space
                 #
-                data = bytes(buffer).decode()
-                q_out.put( (seq, data) )
+                buffer = pending.pop(seq, b'') + buffer
+                if _flags & FLAG_MORE:
+                    pending[seq] = buffer
+                    continue
+                if _flags & FLAG_COMPRESSED:
+                    buffer, _flags = self.decompress(buffer), _flags & ~FLAG_COMPRESSED
+                if _flags & FLAG_EVENT:
+                    q_evt.put(buffer)
+                elif seq!=HEARTBEAT_SEQ:
+                    q_out.put( (seq, (buffer, _flags)) )
         except:
             self.close()
         pass
 
-    def start(self) -> None:
+    def start(self, features=0) -> None:
+        self.features = features
+        self.dumps, self.loads = _codec(features)
+        self.compress, self.decompress = _compressor(features)
         self.shm_res = SharedMemory(name=self.res_id)
         self.sem_res = Semaphore(name='/'+self.res_id)
+        self.sem_res_ack = Semaphore(name='/'+self.res_id+'_ack')
         #
         self.responses = dict()
         self.seq = Value('L', 0) #unsigned long, 4B
-        self.q_in, self.q_out = Queue(), Queue()
-        self.send_process = Process(target=self.send, args=(self.q_in, self.shm_req, self.sem_req, self.seq), daemon=True)
-        self.recv_process = Process(target=self.recv, args=(self.q_out, self.shm_res, self.sem_res), daemon=True)
+        self.q_in, self.q_out, self.q_evt = Queue(), Queue(), Queue()
+        self.send_process = Process(target=self.send, args=(self.q_in, self.shm_req, self.sem_req, self.sem_req_ack), daemon=True)
+        self.recv_process = Process(target=self.recv, args=(self.q_out, self.q_evt, self.shm_res, self.sem_res, self.sem_res_ack), daemon=True)
         self.send_process.start()
         self.recv_process.start()
+        self.start_event_loop()
         time.sleep(0.1) #promise "send" process starts
         pass
 
+    def start_event_loop(self) -> None:
+        def _loop(q_evt):
+            while True:
+                try:
+                    evt = self.loads( q_evt.get() )
+                except:
+                    break
+                for callback in list( self.handlers.get((evt['name'], evt['event']), []) ):
+                    try:
+                        callback(evt['name'], evt['event'], evt['data'])
+                    except Exception as e:
+                        print('event handler failed: %s'%e)
+            pass
+        self.event_thread = threading.Thread(target=_loop, args=(self.q_evt,), daemon=True)
+        self.event_thread.start()
+        pass
+
+    def subscribe(self, name, event, callback) -> None:
+        if not (self.features & FEATURE_PUSH_EVENTS):
+            raise CapabilityError(_ERRCODE.UNKNOWN_COMMAND, 'push events not negotiated.')
+        _key = (name, event)
+        if _key not in self.handlers:
+            self.request(_COMMAND.SUBSCRIBE, name, event)
+            self.handlers[_key] = list()
+        self.handlers[_key].append(callback)
+        pass
+
+    def unsubscribe(self, name, event, callback=None) -> None:
+        _key = (name, event)
+        _callbacks = self.handlers.get(_key, [])
+        if callback in _callbacks:
+            _callbacks.remove(callback)
+        if callback is None or not _callbacks:
+            self.handlers.pop(_key, None)
+            self.request(_COMMAND.UNSUBSCRIBE, name, event)
+        pass
+
     def close(self) -> None:
         # stop the processes
         try:
@@ -138,6 +339,7 @@ class ShmManager:
             self.q_in = None
             self.q_out = None
             self.responses = None
+            self.handlers = dict()
         # shm_res: close and unlink
         try:
             self.shm_res.close()
@@ -145,11 +347,12 @@ class ShmManager:
         except:
             pass
         # sem_res: close and unlink
-        try:
-            self.sem_res.close()
-            self.sem_res.unlink()
-        except:
-            pass
+        for _sem in [self.sem_res, self.sem_res_ack]:
+            try:
+                _sem.close()
+                _sem.unlink()
+            except:
+                pass
         # shm_req: close and unlink
         if self.shm_req is not None:
             try:
@@ -159,24 +362,73 @@ class ShmManager:
             except:
                 pass
         # sen_req: close and unlink
-        if self.sem_req is not None:
-            try:
-                self.sem_req.close()
-                self.sem_req.unlink()
-            except:
-                pass
+        for _sem in [self.sem_req, self.sem_req_ack]:
+            if _sem is not None:
+                try:
+                    _sem.close()
+                    _sem.unlink()
+                except:
+                    pass
         pass
 
+    def frame_limit(self) -> int:
+        return self.shm_req.size - RING_CTRL_LEN - REQ_HEADER.size
+
+    def _encode(self, body) -> tuple:
+        """Return (flags, data) of request body, with the bytes appended as blobs if negotiated, or else in base64."""
+        blobs = list()
+        def _ref(x):
+            if isinstance(x, bytes):
+                if not (self.features & FEATURE_BINARY):
+                    return {'$b64': base64.b64encode(x).decode()}
+                _offset = sum(len(b) for b in blobs)
+                blobs.append(x)
+                return {'$blob': [_offset, len(x)]}
+            if isinstance(x, list):
+                return [_ref(item) for item in x]
+            if isinstance(x, dict):
+                return {k:_ref(v) for k,v in x.items()}
+            return x
+        if body=='':
+            return (0, b'')
+        data = self.dumps( _ref(body) )
+        if not blobs:
+            return (0, data)
+        return (FLAG_BINARY, BIN_PREFIX.pack(len(data)) + data + b''.join(blobs))
+
+    def _unwrap(self, response):
+        #response envelope: {'ok':result} or {'err':{'code','message'}}
+        data, flags = response
+        blobs = b''
+        if flags & FLAG_BINARY:
+            (_len,) = BIN_PREFIX.unpack_from(data)
+            data, blobs = data[BIN_PREFIX.size:BIN_PREFIX.size+_len], data[BIN_PREFIX.size+_len:]
+        res = self.loads(data)
+        if 'err' in res:
+            raise CapabilityError( _ERRCODE(res['err']['code']), res['err']['message'] )
+        def _resolve(x):
+            if isinstance(x, dict) and '$blob' in x:
+                _offset, _len = x['$blob']
+                return blobs[_offset:_offset+_len]
+            if isinstance(x, dict) and '$b64' in x:
+                return base64.b64decode(x['$b64'])
+            if isinstance(x, list):
+                return [_resolve(item) for item in x]
+            if isinstance(x, dict):
+                return {k:_resolve(v) for k,v in x.items()}
+            return x
+        return _resolve(res['ok'])
+
     def get_response(self, seq, blocking=True, timeout=-1):
         data = self.responses.pop(seq, None)
         if data is not None:
-            return json.loads(data)
+            return self._unwrap(data)
         #
         def _process(res):
             _seq, _data = res
             result = None
             if _seq==seq:
-                result = json.loads(_data)
+                result = _data
             else:
                 self.responses.update({_seq:_data})
             return result
@@ -186,48 +438,86 @@ class ShmManager:
             exit_bounded = lambda: _ddl - time.time() < 0
             q_get = lambda: self.q_out.get(timeout=_ddl-time.time())
         else:
-            exit_bounded = False
+            exit_bounded = lambda: True
             q_get = lambda: self.q_out.get_nowait()
         #
         data = _process( q_get() )
-        while (data is not None) or exit_bounded():
+        while (data is None) and not exit_bounded():
             data = _process( q_get() )
-        return data
+        return None if data is None else self._unwrap(data)
 
     def request_async(self, command: _COMMAND, *args, **kwargs) -> int:
         request_format = {
             _COMMAND.ALIVE:        lambda :(_COMMAND.ALIVE, ''),
             _COMMAND.REGISTER:     lambda name:(_COMMAND.REGISTER, 
-                json.dumps({'name': name})
+                {'name': name}
             ),
-            _COMMAND.UNREGISTER:   lambda name:(_COMMAND.UNREGISTER,
-                json.dumps({'name': name})
+            _COMMAND.UNREGISTER:   lambda name, sig:(_COMMAND.UNREGISTER,
+                {'name': name, 'sig': sig}
             ),
-            _COMMAND.CALL:         lambda sig, func, args:(_COMMAND.CALL,
-                json.dumps({'sig':sig, 'func':func, 'args':args})
+            _COMMAND.CALL:         lambda sig, func, args, deadline=None:(_COMMAND.CALL,
+                {'sig':sig, 'func':func, 'args':args, 'deadline':deadline}
             ),
             _COMMAND.ONE_WAY:      lambda sig, func, args:(_COMMAND.ONE_WAY,
-                json.dumps({'sig':sig, 'func':func, 'args':args})
+                {'sig':sig, 'func':func, 'args':args}
+            ),
+            _COMMAND.CHAIN_CALL:   lambda sig_func_args_table, deadline=None, all=False:(_COMMAND.CHAIN_CALL,
+                {'sig_func_args_table':sig_func_args_table, 'deadline':deadline, 'all':all}
+            ),
+            _COMMAND.BATCH:        lambda batch, deadline=None:(_COMMAND.BATCH,
+                {'batch':batch, 'deadline':deadline}
+            ),
+            _COMMAND.CANCEL:       lambda seq:(_COMMAND.CANCEL,
+                {'seq':seq}
             ),
-            _COMMAND.CHAIN_CALL:   lambda sig_func_args_table:(_COMMAND.CHAIN_CALL,
-                json.dumps({'sig_func_args_table':sig_func_args_table})
+            _COMMAND.SUBSCRIBE:    lambda name, event:(_COMMAND.SUBSCRIBE,
+                {'name':name, 'event':event}
+            ),
+            _COMMAND.UNSUBSCRIBE:  lambda name, event:(_COMMAND.UNSUBSCRIBE,
+                {'name':name, 'event':event}
             )
         }
+        _command, _body = request_format[command](*args, **kwargs)
+        _flags, _data = self._encode(_body)
+        if self.compress and len(_data) > COMPRESS_THRESHOLD:
+            _compressed = self.compress(_data)
+            if len(_compressed) < len(_data):
+                _flags, _data = _flags | FLAG_COMPRESSED, _compressed
+        if not (self.features & FEATURE_CHUNKING) and len(_data) > self.frame_limit():
+            raise CapabilityError(_ERRCODE.PAYLOAD_TOO_LARGE, 'request exceeds segment size without chunking.')
         with self.seq.get_lock(): #read-and-write
             _seq = self.seq.value + 1
             self.seq.value = _seq
-        self.q_in.put( request_format[command](*args, **kwargs) )
+        self.q_in.put( (_seq, _command, _data, _flags) )
         return _seq
 
     def request(self, command: _COMMAND, *args, **kwargs):
+        _timeout = kwargs.pop('timeout', -1)
         _seq = self.request_async(command, *args, **kwargs)
         
         if command==_COMMAND.ONE_WAY or command==_COMMAND.UNREGISTER:
             return None
-        if 'timeout' in kwargs:
-            return self.get_response(_seq, timeout=kwargs['timeout'])
-        else:
-            return self.get_response(_seq, timeout=-1)
+        return self.get_response(_seq, timeout=_timeout)
+
+    def batch(self, sig_func_args_table, deadline=None) -> list:
+        """Call the independent functions in one request; return the results in order, with `CapabilityError` for the failed ones."""
+        results = self.request(_COMMAND.BATCH, sig_func_args_table, deadline=deadline)
+        return self._unwrap_all(results)
+
+    def chain_all(self, sig_func_args_table, deadline=None) -> list:
+        """Call the functions in chain; return the results of all the steps like `batch`.
+        The steps depending on a failed one are `UPSTREAM_FAILED`, telling which step failed and why."""
+        results = self.request(_COMMAND.CHAIN_CALL, sig_func_args_table, deadline=deadline, all=True)
+        return self._unwrap_all(results)
+
+    @staticmethod
+    def _unwrap_all(results) -> list:
+        return [ x['ok'] if 'ok' in x else CapabilityError(_ERRCODE(x['err']['code']), x['err']['message'])
+                    for x in results ]
+
+    def cancel(self, seq) -> bool:
+        """Cancel the in-flight call `seq`; it is answered with `CANCELLED` if not yet returned."""
+        return self.request(_COMMAND.CANCEL, seq)
 
     def is_alive(self) -> bool:
         _proc_alive = self.send_process.is_alive() and self.recv_process.is_alive()
@@ -244,6 +534,95 @@ class ShmManager:
 
     pass
 
+class SockManager(ShmManager):
+    """Frame requests and responses over the handshake socket instead of shared memory."""
+    def __init__(self, _id) -> None:
+        self.sock = None
+        self.shm_req, self.shm_res = None, None
+        self.sem_req, self.sem_req_ack = None, None
+        self.sem_res, self.sem_res_ack = None, None
+        self.features = 0
+        self.handlers = dict()
+        pass
+
+    def send(self, q_in: Queue, sock: socket.socket) -> None:
+        try:
+            signal.signal(signal.SIGTERM, lambda: (_ for _ in ()).throw(Exception()) )
+            while True:
+                try:
+                    seq, command, data, flags = q_in.get(timeout=HEARTBEAT_INTERVAL)
+                except Empty:
+                    seq, command, data, flags = HEARTBEAT_SEQ, _COMMAND.ALIVE, b'', 0
+                sock.sendall( REQ_HEADER.pack(seq, command.value, flags, len(data)) + data )
+                time.sleep(0) #transfer to other process
+        except:
+            self.close()
+        pass
+
+    def recv(self, q_out: Queue, q_evt: Queue, sock: socket.socket) -> None:
+        def _recv_exact(size):
+            buffer = b''
+            while len(buffer) < size:
+                _tmp = sock.recv(size - len(buffer))
+                if not _tmp:
+                    raise ConnectionError('connection closed.')
+                buffer += _tmp
+            return buffer
+        #
+        try:
+            signal.signal(signal.SIGTERM, lambda: (_ for _ in ()).throw(Exception()) )
+            while True:
+                seq, _flags, _size = RES_HEADER.unpack( _recv_exact(RES_HEADER.size) )
+                data = _recv_exact(_size)
+                if _flags & FLAG_COMPRESSED:
+                    data, _flags = self.decompress(data), _flags & ~FLAG_COMPRESSED
+                if _flags & FLAG_EVENT:
+                    q_evt.put(data)
+                elif seq!=HEARTBEAT_SEQ:
+                    q_out.put( (seq, (data, _flags)) )
+        except:
+            self.close()
+        pass
+
+    def start(self, features=0, sock=None) -> None:
+        self.features = features
+        self.dumps, self.loads = _codec(features)
+        self.compress, self.decompress = _compressor(features)
+        self.sock = sock
+        #
+        self.responses = dict()
+        self.seq = Value('L', 0) #unsigned long, 4B
+        self.q_in, self.q_out, self.q_evt = Queue(), Queue(), Queue()
+        self.send_process = Process(target=self.send, args=(self.q_in, self.sock), daemon=True)
+        self.recv_process = Process(target=self.recv, args=(self.q_out, self.q_evt, self.sock), daemon=True)
+        self.send_process.start()
+        self.recv_process.start()
+        self.start_event_loop()
+        pass
+
+    def frame_limit(self) -> int:
+        return float('inf') #frames are length-prefixed
+
+    def close(self) -> None:
+        try:
+            self.send_process.close()
+            self.recv_process.close()
+        except:
+            pass
+        finally:
+            self.q_in = None
+            self.q_out = None
+            self.responses = None
+        if self.sock is not None:
+            try:
+                self.sock.shutdown(socket.SHUT_RDWR)
+                self.sock.close()
+            except:
+                pass
+        pass
+
+    pass
+
 class CapabilityHandle:
     def __init__(self, server: ShmManager, name, mode) -> None:
         res = server.request(_COMMAND.REGISTER, name)
@@ -252,8 +631,10 @@ class CapabilityHandle:
         #
         self._server = server
         self.mode = mode
+        self._name = name
         self._sig = res['sig']
         self._spec = res['spec']
+        self.deadline = None #in milliseconds for each call, if set
         #
         self._sig_func_args_table = None #for lazy response
         pass
@@ -268,9 +649,10 @@ class CapabilityHandle:
             @wraps(name)
             def _wrapper(*args, **kwargs):
                 _sig_func_args_table = list()
+                _args = list()
                 # check spec validation with *args and **kwargs
                 for i,x in enumerate(args_spec):
-                    _name, _type = x.keys()[0], x.values()[0]
+                    _name, _type = next(iter( x.items() ))
                     if i < len(args):
                         _arg = args[i]
                     elif _name in kwargs:
@@ -278,22 +660,22 @@ class CapabilityHandle:
                     else:
                         raise Exception('Input argument missing: %s.'%_name)
                     #
-                    if __validate(_type, _arg):
-                        args_spec[i] = _arg #feed in: dict -> arg
+                    if _validate(_type, _arg):
+                        _args.append(_arg) #feed in: dict -> arg
                         if isinstance(_arg, AnyType):
                             _sig_func_args_table.extend(_arg.table)
                     else:
                         raise Exception('Input type mismatch: "%r" for type "%s".'%(_arg, _type))
                     pass
-                _sig_func_args_table.extend( [self._sig, name, args_spec] )
+                _sig_func_args_table.append( [self._sig, name, _args] )
                 # wrap request method, lazy or not
-                if len(_sig_func_args_table) > 0 or _mode=='lazy':
+                if len(_sig_func_args_table) > 1 or _mode=='lazy':
                     self._sig_func_args_table = _sig_func_args_table
                     res = AnyType( 'restype_%s_%s'%(self._sig, name), _restype, _sig_func_args_table )
                 elif _mode=='one-way':
                     res = self._server.request(_COMMAND.ONE_WAY, *_sig_func_args_table[0])
                 else:
-                    res = self._server.request(_COMMAND.CALL, *_sig_func_args_table[0])
+                    res = self._server.request(_COMMAND.CALL, *_sig_func_args_table[0], deadline=self.deadline)
                 return res
             
             return _wrapper
@@ -301,13 +683,17 @@ class CapabilityHandle:
             return super().__getattribute__(name)
         pass
 
-    def execute(self, blocking=True): #not support non-blocking now
+    def execute(self, blocking=True, all=False): #not support non-blocking now
+        if self._sig_func_args_table is not None and all:
+            res = self._server.chain_all(self._sig_func_args_table, deadline=self.deadline)
+            self._sig_func_args_table = None
+            return res
         if self._sig_func_args_table is not None:
             _request_method = self._server.request if blocking else self._server.request_async
-            if len(self._sig_func_args_table) > 0:
-                res = _request_method(_COMMAND.CHAIN_CALL, self._sig_func_args_table)
+            if len(self._sig_func_args_table) > 1:
+                res = _request_method(_COMMAND.CHAIN_CALL, self._sig_func_args_table, deadline=self.deadline)
             else:
-                res = _request_method(_COMMAND.CALL, *self._sig_func_args_table[0])
+                res = _request_method(_COMMAND.CALL, *self._sig_func_args_table[0], deadline=self.deadline)
             self._sig_func_args_table = None
             return res
         else:
@@ -315,8 +701,8 @@ class CapabilityHandle:
         pass
 
     def drop(self):
-        if len(self._spec):
-            self._server.request(_COMMAND.UNREGISTER, self._spec['name'])
+        if self._sig is not None:
+            self._server.request(_COMMAND.UNREGISTER, self._name, self._sig)
         del self._spec
         self._spec = dict()
         self._sig = None
@@ -325,10 +711,12 @@ class CapabilityHandle:
     pass
 
 class CapabilityLibrary:
-    def __init__(self, remote='') -> None:
+    def __init__(self, remote='', port=None, transport='shm') -> None:
         if remote:
             raise Exception('Not support remote connection now.')
         random.seed( time.time() )
+        self.port = port #connect via TCP if specified, otherwise unix socket
+        self.transport = transport #'shm' or 'socket'
         self.__id = GET_RANDOM_ID()
         self.__server = None
         self.capability = dict()
@@ -338,27 +726,47 @@ class CapabilityLibrary:
         if self.__server and self.__server.is_alive():
             return
         #
-        _addr = ('', VDM_SERVER_PORT)
-        _sock = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
-        __server = ShmManager(self.__id)
+        if self.port:
+            _addr = ('127.0.0.1', self.port)
+            _sock = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
+        else:
+            _addr = VDM_SERVER_SOCK
+            _sock = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+        if self.transport=='socket':
+            __server = SockManager(self.__id)
+            _features = SUPPORTED_FEATURES | FEATURE_SOCKET_TRANSPORT
+        else:
+            __server = ShmManager(self.__id)
+            _features = SUPPORTED_FEATURES
+        _keep_sock = False
         try:
-            # handshake-I
+            # handshake-I: id, version and features
             _sock.connect(_addr)
-            _sock.sendall(self.__id)
-            # handshake-II
-            _tmp = _sock.recv(VDM_CLIENT_ID_LEN+1) #+1 for '\0'
-            if _tmp==self.__id:
-                self.__server = __server
-                self.__server.start()
-            else:
+            _sock.sendall( HS_HELLO.pack(self.__id.encode(), PROTOCOL_VERSION, _features) )
+            # handshake-II: negotiated version and features
+            _tmp = _sock.recv(HS_WELCOME.size)
+            _id, _version, _features, _status, _msg_len = HS_WELCOME.unpack(_tmp)
+            if _status != 0:
+                _msg = _sock.recv(_msg_len).decode()
+                raise Exception('Connection Rejected: %s'%_msg)
+            if _id!=self.__id.encode():
                 raise Exception('Invalid Connection: %s'%_tmp)
+            if self.transport=='socket' and not (_features & FEATURE_SOCKET_TRANSPORT):
+                raise Exception('Connection Rejected: socket transport not supported.')
             # handshake-III
-            _sock.sendall(self.__id)
+            _sock.sendall(self.__id.encode())
+            self.__server = __server
+            if self.transport=='socket':
+                self.__server.start(_features, _sock)
+                _keep_sock = True
+            else:
+                self.__server.start(_features)
         except Exception as e:
             __server.close()
             raise e
         finally:
-            _sock.close()
+            if not _keep_sock:
+                _sock.close()
         pass
 
     def disconnect(self) -> None:
@@ -373,6 +781,24 @@ class CapabilityLibrary:
         self.__server.close()
         pass
 
+    def subscribe(self, name:str, event:str, callback) -> None:
+        """Call `callback(name, event, data)` whenever capability `name` emits `event`."""
+        self.__server.subscribe(name, event, callback)
+        pass
+
+    def unsubscribe(self, name:str, event:str, callback=None) -> None:
+        self.__server.unsubscribe(name, event, callback)
+        pass
+
+    def batch(self, *calls, deadline=None) -> list:
+        """Send the independent calls of 'lazy' capabilities in one request, see `ShmManager.batch`."""
+        _table = list()
+        for _call in calls:
+            if not isinstance(_call, AnyType) or len(_call.table) > 1:
+                raise Exception('Only independent lazy calls allowed in batch.')
+            _table.append(_call.table[0])
+        return self.__server.batch(_table, deadline)
+
     def getCapability(self, name:str, mode=None) -> CapabilityHandle:
         if name in self.capability.keys():
             return self.capability[name]
@@ -1,6 +1,10 @@
 #ifndef __ARS_API_H__
 #define __ARS_API_H__
 
+#include <stddef.h>
+#include <stdint.h>
+#include <stdbool.h>
+
 extern int onStart(void);
 extern int onStop(void);
 
@@ -10,4 +14,40 @@ extern int onClose(void);
 
 extern int onTrigger(void *);
 
+/* the arguments and result are passed as declared in metadata:
+   "int" -> int64_t, "float" -> double, "bool" -> bool, "bytes" -> vdm_bytes_t (by value),
+   and any other type -> const char * (NUL-terminated) */
+typedef struct {
+    const uint8_t *ptr;
+    size_t len;
+} vdm_bytes_t;
+
+typedef void (*vdm_emit_t)(void *ctx, const char *event, const char *data);
+/* optional: keep the emitter to notify the subscribed clients */
+extern void vdm_set_emitter(void *ctx, vdm_emit_t emit);
+
+/* register and call other capabilities during a call, under its deadline and cancellation;
+   the returned strings are released with `free_str`, and `NULL` is returned on failure
+   with `*err` (if not NULL) set to the error message, released with `free_str` as well.
+   `execute` fails on bytes results, which `execute_bytes` returns into `*res` (0 on success),
+   released with `free_bytes` */
+typedef struct {
+    void *ctx;
+    char *(*register_capability)(void *ctx, const char *name, char **err);
+    int (*unregister_capability)(void *ctx, const char *sig);
+    char *(*execute)(void *ctx, const char *sig, const char *func,
+                     const char *const *args, size_t argc, char **err);
+    void (*free_str)(char *s);
+    int (*execute_bytes)(void *ctx, const char *sig, const char *func,
+                         const char *const *args, size_t argc, vdm_bytes_t *res, char **err);
+    void (*free_bytes)(vdm_bytes_t data);
+} vdm_host_t;
+/* optional: keep the host, valid until the capability is unloaded */
+extern void vdm_set_host(const vdm_host_t *host);
+
+/* string and bytes results are borrowed by default, and must stay valid after return (e.g. static);
+   those declared `"owned": true` in metadata are allocated per call, and released with `vdm_free`
+   once copied, which is then required. A NULL string, or NULL bytes of non-zero length, is a failure. */
+extern void vdm_free(void *ptr);
+
 #endif
\ No newline at end of file